@@ -0,0 +1,74 @@
+#[macro_use]
+extern crate criterion;
+extern crate xmlbufrw;
+
+use criterion::{Criterion, Throughput};
+use xmlbufrw::reader::ReaderBuilder;
+
+use std::io::{BufReader, Read};
+
+// About 4MB of utf-8, mostly ascii markup around text that isn't
+fn utf8_doc() -> String {
+    let mut doc = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<entries>\n");
+    let mut i = 0;
+    while doc.len() < 4 * 1024 * 1024 {
+        doc.push_str(&format!(
+            "  <entry id=\"{}\" lang=\"ja\">\u{65e5}\u{672c}\u{8a9e}\u{306e}\u{30c6}\u{30ad}\u{30b9}\u{30c8} caf\u{e9} na\u{ef}ve</entry>\n",
+            i
+        ));
+        i += 1;
+    }
+    doc.push_str("</entries>\n");
+    doc
+}
+
+fn read_to_string(builder: &ReaderBuilder, doc: &[u8]) -> String {
+    let mut decoding_reader = builder.build(doc).expect("Failed initializing read buffer");
+    let mut utf8_encoded_doc = String::new();
+    decoding_reader
+        .read_to_string(&mut utf8_encoded_doc)
+        .expect("Failed decoding input data");
+    utf8_encoded_doc
+}
+
+fn bench_utf8(c: &mut Criterion) {
+    let doc = utf8_doc();
+    let mut group = c.benchmark_group("utf8");
+    group.throughput(Throughput::Bytes(doc.len() as u64));
+
+    group.bench_function("decoder", |b| {
+        let mut builder = ReaderBuilder::new();
+        builder.utf8_passthrough(false);
+        b.iter(|| read_to_string(&builder, doc.as_bytes()))
+    });
+    group.bench_function("passthrough", |b| {
+        let builder = ReaderBuilder::new();
+        b.iter(|| read_to_string(&builder, doc.as_bytes()))
+    });
+    group.bench_function("passthrough_fill_str", |b| {
+        b.iter(|| {
+            let mut decoding_reader = ReaderBuilder::new()
+                .build(BufReader::with_capacity(64 * 1024, doc.as_bytes()))
+                .expect("Failed initializing read buffer");
+            let mut chars = 0;
+            loop {
+                let text_len = {
+                    let text = decoding_reader
+                        .fill_str()
+                        .expect("Failed decoding input data");
+                    chars += text.len();
+                    text.len()
+                };
+                if text_len == 0 {
+                    break;
+                }
+                decoding_reader.consume_str(text_len);
+            }
+            chars
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_utf8);
+criterion_main!(benches);
@@ -0,0 +1,682 @@
+use enc_detect::DetectionReport;
+use error::{Error, Result};
+use position::Position;
+use reader::{
+    DecodingReader, MalformedInputHandling, PendingInput, ReaderBuilder, XmlDeclHandling,
+};
+use xml_decl::XmlDecl;
+
+use futures_io::{AsyncBufRead, AsyncRead};
+
+use std::future::Future;
+use std::io;
+use std::ops::Range;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+pub fn new<R: AsyncRead + Unpin>(inner: R) -> Detect<R> {
+    ReaderBuilder::new().build_async(inner)
+}
+
+pub fn with_capacity<R: AsyncRead + Unpin>(inner: R, capacity: usize) -> Detect<R> {
+    ReaderBuilder::new().capacity(capacity).build_async(inner)
+}
+
+pub fn with_capacity_and_input_encoding<R: AsyncRead + Unpin>(
+    inner: R,
+    capacity: usize,
+    suggested_encoding: Option<String>,
+) -> Detect<R> {
+    let mut builder = ReaderBuilder::new();
+    builder.capacity(capacity);
+    if let Some(suggested_encoding) = suggested_encoding {
+        builder.suggested_encoding(suggested_encoding);
+    }
+    builder.build_async(inner)
+}
+
+impl ReaderBuilder {
+    /// Like `build`, but for an `AsyncRead`. Detecting the input encoding takes reads of its own,
+    /// so this returns a future that resolves to the reader once detection is done.
+    pub fn build_async<R: AsyncRead + Unpin>(&self, inner: R) -> Detect<R> {
+        Detect {
+            builder: self.clone(),
+            inner: Some(inner),
+            prefix: Vec::new(),
+        }
+    }
+}
+
+// Detection rarely needs more of the document than its xml declaration
+const DETECTION_READ_LEN: usize = 256;
+
+/// Detects the input encoding of an `AsyncRead`, and resolves to an `AsyncDecodingReader` for it.
+pub struct Detect<R> {
+    builder: ReaderBuilder,
+    inner: Option<R>,
+    // The start of the document, which detection is run over again as it grows
+    prefix: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin> Future for Detect<R> {
+    type Output = Result<AsyncDecodingReader<R>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let mut inner = this.inner.take().expect("Detect polled after it completed");
+        loop {
+            let start = this.prefix.len();
+            this.prefix.resize(start + DETECTION_READ_LEN, 0);
+            let result = Pin::new(&mut inner).poll_read(cx, &mut this.prefix[start..]);
+            this.prefix.truncate(match result {
+                Poll::Ready(Ok(read)) => start + read,
+                _ => start,
+            });
+            let eof = match result {
+                Poll::Pending => {
+                    this.inner = Some(inner);
+                    return Poll::Pending;
+                }
+                Poll::Ready(Ok(read)) => read == 0,
+                Poll::Ready(Err(ref e)) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(Error::from(e))),
+            };
+            if let Some(result) = this.builder.build_from_prefix(&this.prefix, eof) {
+                return Poll::Ready(result.map(|decoding_reader| {
+                    // The capacity is at least 1, so a read of 0 bytes is always the end of input
+                    let read_buf = vec![0; decoding_reader.capacity()];
+                    AsyncDecodingReader {
+                        inner,
+                        decoding_reader,
+                        read_buf,
+                    }
+                }));
+            }
+        }
+    }
+}
+
+/// Reads from the wrapped `AsyncRead` in the detected input encoding, and yields utf-8.
+pub struct AsyncDecodingReader<R> {
+    inner: R,
+    decoding_reader: DecodingReader<PendingInput>,
+    read_buf: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncDecodingReader<R> {
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// How the input encoding was decided.
+    pub fn detection_report(&self) -> &DetectionReport {
+        self.decoding_reader.detection_report()
+    }
+
+    /// The document's xml declaration, if it has one.
+    pub fn xml_decl(&self) -> Option<&XmlDecl> {
+        self.decoding_reader.xml_decl()
+    }
+
+    /// The version from the document's xml declaration, if it has one.
+    pub fn version(&self) -> Option<&str> {
+        self.decoding_reader.version()
+    }
+
+    /// The standalone flag from the document's xml declaration, if it has one.
+    pub fn standalone(&self) -> Option<bool> {
+        self.decoding_reader.standalone()
+    }
+
+    /// See `DecodingReader::set_xml_decl_handling`.
+    pub fn set_xml_decl_handling(&mut self, handling: XmlDeclHandling) {
+        self.decoding_reader.set_xml_decl_handling(handling);
+    }
+
+    /// See `DecodingReader::set_eol_normalization`.
+    pub fn set_eol_normalization(&mut self, enabled: bool) {
+        self.decoding_reader.set_eol_normalization(enabled);
+    }
+
+    /// See `DecodingReader::set_char_validation`.
+    pub fn set_char_validation(&mut self, enabled: bool) {
+        self.decoding_reader.set_char_validation(enabled);
+    }
+
+    /// See `DecodingReader::set_malformed_input_handling`.
+    pub fn set_malformed_input_handling(&mut self, handling: MalformedInputHandling) {
+        self.decoding_reader.set_malformed_input_handling(handling);
+    }
+
+    /// Where each malformed sequence that was replaced or skipped started in the document.
+    pub fn substitutions(&self) -> &[usize] {
+        self.decoding_reader.substitutions()
+    }
+
+    /// Where the next char to be read comes from.
+    pub fn position(&self) -> Position {
+        self.decoding_reader.position()
+    }
+
+    /// See `DecodingReader::set_offset_map`.
+    pub fn set_offset_map(&mut self, interval: Option<usize>) {
+        self.decoding_reader.set_offset_map(interval);
+    }
+
+    /// See `DecodingReader::source_range`.
+    pub fn source_range(&self, decoded: Range<usize>) -> Option<Range<usize>> {
+        self.decoding_reader.source_range(decoded)
+    }
+
+    /// See `DecodingReader::set_size_limit`.
+    pub fn set_size_limit(&mut self, limit: Option<usize>) {
+        self.decoding_reader.set_size_limit(limit);
+    }
+
+    /// See `DecodingReader::set_utf8_passthrough`.
+    pub fn set_utf8_passthrough(&mut self, enabled: bool) {
+        self.decoding_reader.set_utf8_passthrough(enabled);
+    }
+
+    // Decodes until there's output that hasn't been read, or the document is done. Input is
+    // only polled for once everything that's already been read has been decoded.
+    fn poll_fill_output(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        loop {
+            match self.decoding_reader.fill_output_buf() {
+                Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock => {}
+                result => return Poll::Ready(result.map_err(io::Error::from)),
+            }
+            match Pin::new(&mut self.inner).poll_read(cx, &mut self.read_buf) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Ok(0)) => self.decoding_reader.get_mut().set_eof(),
+                Poll::Ready(Ok(read)) => {
+                    self.decoding_reader.get_mut().push(&self.read_buf[..read])
+                }
+                Poll::Ready(Err(ref e)) if e.kind() == io::ErrorKind::Interrupted => {}
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            }
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for AsyncDecodingReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        match this.poll_fill_output(cx) {
+            Poll::Ready(Ok(())) => {}
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Pending => return Poll::Pending,
+        }
+        let available = this.decoding_reader.unread_output();
+        let bytes_read = std::cmp::min(buf.len(), available.len());
+        buf[..bytes_read].copy_from_slice(&available[..bytes_read]);
+        this.decoding_reader.consume_output(bytes_read);
+        Poll::Ready(Ok(bytes_read))
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncBufRead for AsyncDecodingReader<R> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        match this.poll_fill_output(cx) {
+            Poll::Ready(Ok(())) => Poll::Ready(Ok(this.decoding_reader.unread_output())),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        self.get_mut().decoding_reader.consume_output(amt);
+    }
+}
+
+#[cfg(test)]
+mod async_reader_tests {
+    use super::*;
+    use futures::executor::block_on;
+    use futures::io::{AsyncBufReadExt, AsyncReadExt};
+    use futures::task::noop_waker_ref;
+    use std::io::Read;
+    use DEFAULT_BUF_SIZE;
+
+    // Each test doc, and the validation doc with the same text in utf-8, no bom
+    const DOCS: &[(&[u8], &[u8])] = &[
+        (
+            include_bytes!("../tests/big5/doc_xmldecl_encodingdecl.xml") as &[u8],
+            include_bytes!("../tests/validation/big5_xmldecl_encodingdecl.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/euc-jp/doc_xmldecl_encodingdecl.xml") as &[u8],
+            include_bytes!("../tests/validation/euc-jp_xmldecl_encodingdecl.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/euc-kr/doc_xmldecl_encodingdecl.xml") as &[u8],
+            include_bytes!("../tests/validation/euc-kr_xmldecl_encodingdecl.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/gb18030/doc_xmldecl_encodingdecl.xml") as &[u8],
+            include_bytes!("../tests/validation/gb18030_xmldecl_encodingdecl.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/gbk/doc_xmldecl_encodingdecl.xml") as &[u8],
+            include_bytes!("../tests/validation/gbk_xmldecl_encodingdecl.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/ibm037/doc_xmldecl.xml") as &[u8],
+            include_bytes!("../tests/validation/ibm037_xmldecl.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/ibm037/doc_xmldecl_encodingdecl.xml") as &[u8],
+            include_bytes!("../tests/validation/ibm037_xmldecl_encodingdecl.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/ibm1047/doc_xmldecl_encodingdecl.xml") as &[u8],
+            include_bytes!("../tests/validation/ibm1047_xmldecl_encodingdecl.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/ibm500/doc_xmldecl_encodingdecl.xml") as &[u8],
+            include_bytes!("../tests/validation/ibm500_xmldecl_encodingdecl.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/iso-2022-jp/doc_xmldecl_encodingdecl.xml") as &[u8],
+            include_bytes!("../tests/validation/iso-2022-jp_xmldecl_encodingdecl.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/iso-8859-1/doc_xmldecl_encodingdecl.xml") as &[u8],
+            include_bytes!("../tests/validation/iso-8859-1_xmldecl_encodingdecl.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/koi8-r/doc_xmldecl_encodingdecl.xml") as &[u8],
+            include_bytes!("../tests/validation/koi8-r_xmldecl_encodingdecl.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/shift_jis/doc_xmldecl_encodingdecl.xml") as &[u8],
+            include_bytes!("../tests/validation/shift_jis_xmldecl_encodingdecl.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/utf16be/doc_xmldecl.xml") as &[u8],
+            include_bytes!("../tests/validation/utf16be_xmldecl.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/utf16be/doc_xmldecl_encodingdecl.xml") as &[u8],
+            include_bytes!("../tests/validation/utf16be_xmldecl_encodingdecl.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/utf16be_bom/doc.xml") as &[u8],
+            include_bytes!("../tests/validation/utf16be.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/utf16be_bom/doc_xmldecl.xml") as &[u8],
+            include_bytes!("../tests/validation/utf16be_xmldecl.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/utf16be_bom/doc_xmldecl_encodingdecl.xml") as &[u8],
+            include_bytes!("../tests/validation/utf16be_xmldecl_encodingdecl.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/utf16le/doc_xmldecl.xml") as &[u8],
+            include_bytes!("../tests/validation/utf16le_xmldecl.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/utf16le/doc_xmldecl_encodingdecl.xml") as &[u8],
+            include_bytes!("../tests/validation/utf16le_xmldecl_encodingdecl.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/utf16le_bom/doc.xml") as &[u8],
+            include_bytes!("../tests/validation/utf16le.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/utf16le_bom/doc_xmldecl.xml") as &[u8],
+            include_bytes!("../tests/validation/utf16le_xmldecl.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/utf16le_bom/doc_xmldecl_encodingdecl.xml") as &[u8],
+            include_bytes!("../tests/validation/utf16le_xmldecl_encodingdecl.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/utf32be/doc.xml") as &[u8],
+            include_bytes!("../tests/validation/utf32be.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/utf32be/doc_xmldecl.xml") as &[u8],
+            include_bytes!("../tests/validation/utf32be_xmldecl.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/utf32be/doc_xmldecl_encodingdecl.xml") as &[u8],
+            include_bytes!("../tests/validation/utf32be_xmldecl_encodingdecl.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/utf32be_bom/doc.xml") as &[u8],
+            include_bytes!("../tests/validation/utf32be.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/utf32be_bom/doc_xmldecl.xml") as &[u8],
+            include_bytes!("../tests/validation/utf32be_xmldecl.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/utf32be_bom/doc_xmldecl_encodingdecl.xml") as &[u8],
+            include_bytes!("../tests/validation/utf32be_xmldecl_encodingdecl.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/utf32le/doc.xml") as &[u8],
+            include_bytes!("../tests/validation/utf32le.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/utf32le/doc_xmldecl.xml") as &[u8],
+            include_bytes!("../tests/validation/utf32le_xmldecl.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/utf32le/doc_xmldecl_encodingdecl.xml") as &[u8],
+            include_bytes!("../tests/validation/utf32le_xmldecl_encodingdecl.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/utf32le_bom/doc.xml") as &[u8],
+            include_bytes!("../tests/validation/utf32le.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/utf32le_bom/doc_xmldecl.xml") as &[u8],
+            include_bytes!("../tests/validation/utf32le_xmldecl.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/utf32le_bom/doc_xmldecl_encodingdecl.xml") as &[u8],
+            include_bytes!("../tests/validation/utf32le_xmldecl_encodingdecl.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/utf8/doc.xml") as &[u8],
+            include_bytes!("../tests/validation/utf8.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/utf8/doc_xmldecl.xml") as &[u8],
+            include_bytes!("../tests/validation/utf8_xmldecl.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/utf8/doc_xmldecl_encodingdecl.xml") as &[u8],
+            include_bytes!("../tests/validation/utf8_xmldecl_encodingdecl.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/utf8_bom/doc.xml") as &[u8],
+            include_bytes!("../tests/validation/utf8.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/utf8_bom/doc_xmldecl.xml") as &[u8],
+            include_bytes!("../tests/validation/utf8_xmldecl.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/utf8_bom/doc_xmldecl_encodingdecl.xml") as &[u8],
+            include_bytes!("../tests/validation/utf8_xmldecl_encodingdecl.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/windows-1251/doc_xmldecl_encodingdecl.xml") as &[u8],
+            include_bytes!("../tests/validation/windows-1251_xmldecl_encodingdecl.xml") as &[u8],
+        ),
+    ];
+
+    // Makes the reader wait before every byte of input
+    struct Trickle<'a> {
+        data: &'a [u8],
+        ready: bool,
+    }
+
+    impl<'a> Trickle<'a> {
+        fn new(data: &'a [u8]) -> Self {
+            Trickle { data, ready: false }
+        }
+    }
+
+    impl<'a> AsyncRead for Trickle<'a> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            if !self.ready {
+                self.ready = true;
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            self.ready = false;
+            let bytes_read = std::cmp::min(std::cmp::min(buf.len(), 1), self.data.len());
+            buf[..bytes_read].copy_from_slice(&self.data[..bytes_read]);
+            self.data = &self.data[bytes_read..];
+            Poll::Ready(Ok(bytes_read))
+        }
+    }
+
+    fn decode<R: AsyncRead + Unpin>(builder: &ReaderBuilder, inner: R) -> Result<String> {
+        let mut decoding_reader = block_on(builder.build_async(inner))?;
+        let mut utf8_encoded_doc = String::new();
+        block_on(decoding_reader.read_to_string(&mut utf8_encoded_doc))?;
+        Ok(utf8_encoded_doc)
+    }
+
+    #[test]
+    fn test_docs() {
+        for &capacity in &[0, 1, DEFAULT_BUF_SIZE] {
+            let mut builder = ReaderBuilder::new();
+            builder.capacity(capacity);
+            for &(doc, utf8_validation) in DOCS {
+                let utf8_encoded_doc = decode(&builder, doc).expect("Failed decoding input data");
+                assert_eq!(utf8_validation, utf8_encoded_doc.as_bytes());
+                let utf8_encoded_doc =
+                    decode(&builder, Trickle::new(doc)).expect("Failed decoding input data");
+                assert_eq!(utf8_validation, utf8_encoded_doc.as_bytes());
+            }
+        }
+    }
+
+    // Has the start of a document, and then never anything more
+    struct Stalled<'a>(&'a [u8]);
+
+    impl<'a> AsyncRead for Stalled<'a> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            if self.0.is_empty() {
+                return Poll::Pending;
+            }
+            let bytes_read = std::cmp::min(buf.len(), self.0.len());
+            buf[..bytes_read].copy_from_slice(&self.0[..bytes_read]);
+            self.0 = &self.0[bytes_read..];
+            Poll::Ready(Ok(bytes_read))
+        }
+    }
+
+    #[test]
+    fn test_detection_before_eof() {
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let mut detect = new(Stalled(b"<?xml version='1.0'?><stream>"));
+        let mut decoding_reader = match Pin::new(&mut detect).poll(&mut cx) {
+            Poll::Ready(result) => result.expect("Failed initializing read buffer"),
+            Poll::Pending => {
+                panic!("Detection shouldn't wait once the xml declaration is complete")
+            }
+        };
+        let mut buf = [0; 64];
+        match Pin::new(&mut decoding_reader).poll_read(&mut cx, &mut buf) {
+            Poll::Ready(result) => assert_eq!(
+                &buf[..result.expect("Failed decoding input data")],
+                &b"<?xml version='1.0'?><stream>"[..]
+            ),
+            Poll::Pending => panic!("Input that's been read shouldn't wait on more"),
+        }
+        match Pin::new(&mut decoding_reader).poll_read(&mut cx, &mut buf) {
+            Poll::Ready(_) => panic!("There's no more input yet"),
+            Poll::Pending => {}
+        }
+    }
+
+    #[test]
+    fn test_short_docs() {
+        for doc in &[
+            &b""[..],
+            &b"<"[..],
+            &b"\xef\xbb\xbf"[..],
+            &b"\xef\xbb\xbf<a/>"[..],
+        ] {
+            let sync_doc = {
+                let mut decoding_reader = ReaderBuilder::new()
+                    .build(*doc)
+                    .expect("Failed initializing read buffer");
+                let mut utf8_encoded_doc = String::new();
+                decoding_reader
+                    .read_to_string(&mut utf8_encoded_doc)
+                    .expect("Failed decoding input data");
+                utf8_encoded_doc
+            };
+            let builder = ReaderBuilder::new();
+            assert_eq!(
+                decode(&builder, Trickle::new(doc)).expect("Failed decoding input data"),
+                sync_doc
+            );
+        }
+    }
+
+    #[test]
+    fn test_detection_errors() {
+        let builder = ReaderBuilder::new();
+        match decode(
+            &builder,
+            Trickle::new(include_bytes!("../tests/utf16le/doc.xml")),
+        ) {
+            Err(Error::NoBomNoDecl) => {}
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("Utf-16 without a BOM or xml declaration should fail detection"),
+        }
+        match decode(
+            &builder,
+            Trickle::new(b"<?xml version=\"1.0\" encoding=\"no-such-encoding\"?><a/>"),
+        ) {
+            Err(Error::UnsupportedEncoding(_)) => {}
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("An unknown encoding declaration should fail detection"),
+        }
+        match decode(&builder, Trickle::new(b"<?xml version=\"1.0\"")) {
+            Err(Error::MalformedXmlDecl(_)) => {}
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("An unterminated xml declaration should fail detection"),
+        }
+    }
+
+    #[test]
+    fn test_builder() {
+        let doc = include_bytes!("../tests/shift_jis/doc_xmldecl_encodingdecl.xml");
+        let mut builder = ReaderBuilder::new();
+        builder
+            .capacity(16)
+            .xml_decl_handling(XmlDeclHandling::RewriteEncoding)
+            .eol_normalization(true);
+        let mut sync_doc = String::new();
+        builder
+            .build(&doc[..])
+            .expect("Failed initializing read buffer")
+            .read_to_string(&mut sync_doc)
+            .expect("Failed decoding input data");
+
+        let mut decoding_reader = block_on(builder.build_async(Trickle::new(doc)))
+            .expect("Failed initializing read buffer");
+        assert_eq!(
+            decoding_reader.detection_report().encoding,
+            "shift_jis".to_string()
+        );
+        assert_eq!(decoding_reader.version(), Some("1.0"));
+        let mut utf8_encoded_doc = String::new();
+        block_on(decoding_reader.read_to_string(&mut utf8_encoded_doc))
+            .expect("Failed decoding input data");
+        assert_eq!(utf8_encoded_doc, sync_doc);
+        assert_eq!(decoding_reader.position().offset, doc.len());
+    }
+
+    #[test]
+    fn test_setters() {
+        let doc = include_bytes!("../tests/shift_jis/doc_xmldecl_encodingdecl.xml");
+        let mut sync_reader = ReaderBuilder::new()
+            .xml_decl_handling(XmlDeclHandling::Strip)
+            .offset_map(Some(1))
+            .build(&doc[..])
+            .expect("Failed initializing read buffer");
+        let mut sync_doc = String::new();
+        sync_reader
+            .read_to_string(&mut sync_doc)
+            .expect("Failed decoding input data");
+
+        let mut decoding_reader =
+            block_on(new(Trickle::new(doc))).expect("Failed initializing read buffer");
+        decoding_reader.set_xml_decl_handling(XmlDeclHandling::Strip);
+        decoding_reader.set_offset_map(Some(1));
+        decoding_reader.set_utf8_passthrough(false);
+        let mut utf8_encoded_doc = String::new();
+        block_on(decoding_reader.read_to_string(&mut utf8_encoded_doc))
+            .expect("Failed decoding input data");
+        assert_eq!(utf8_encoded_doc, sync_doc);
+        let end = utf8_encoded_doc.len();
+        assert!(decoding_reader.source_range(0..end).is_some());
+        assert_eq!(
+            decoding_reader.source_range(0..end),
+            sync_reader.source_range(0..end)
+        );
+
+        let mut decoding_reader =
+            block_on(new(Trickle::new(doc))).expect("Failed initializing read buffer");
+        decoding_reader.set_size_limit(Some(doc.len() - 1));
+        let mut utf8_encoded_doc = String::new();
+        match block_on(decoding_reader.read_to_string(&mut utf8_encoded_doc)) {
+            Err(e) => match e.get_ref().and_then(|e| e.downcast_ref::<Error>()) {
+                Some(Error::SizeLimitExceeded { limit }) => assert_eq!(*limit, doc.len() - 1),
+                _ => panic!("Unexpected error: {}", e),
+            },
+            Ok(_) => panic!("Accepted a document over the size limit"),
+        }
+    }
+
+    #[test]
+    fn test_malformed_input() {
+        let doc = b"<?xml version=\"1.0\" encoding=\"utf-8\"?><a>\xff</a>";
+        let mut decoding_reader =
+            block_on(new(Trickle::new(doc))).expect("Failed initializing read buffer");
+        let mut utf8_encoded_doc = String::new();
+        match block_on(decoding_reader.read_to_string(&mut utf8_encoded_doc)) {
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {}
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("Malformed input should fail decoding"),
+        }
+
+        let mut decoding_reader =
+            block_on(new(Trickle::new(doc))).expect("Failed initializing read buffer");
+        decoding_reader.set_malformed_input_handling(MalformedInputHandling::Replace);
+        let mut utf8_encoded_doc = String::new();
+        block_on(decoding_reader.read_to_string(&mut utf8_encoded_doc))
+            .expect("Failed decoding input data");
+        assert!(utf8_encoded_doc.ends_with("<a>\u{fffd}</a>"));
+        assert_eq!(decoding_reader.substitutions(), &[41]);
+    }
+
+    #[test]
+    fn test_buf_read() {
+        let doc = include_bytes!("../tests/utf16le_bom/doc_xmldecl.xml");
+        let utf8_validation = include_bytes!("../tests/validation/utf16le_xmldecl.xml");
+        let mut decoding_reader =
+            block_on(with_capacity(Trickle::new(doc), 3)).expect("Failed initializing read buffer");
+        let mut utf8_encoded_doc = Vec::new();
+        loop {
+            let read = block_on(decoding_reader.read_until(b'>', &mut utf8_encoded_doc))
+                .expect("Failed decoding input data");
+            if read == 0 {
+                break;
+            }
+            assert!(
+                utf8_encoded_doc.ends_with(b">") || utf8_encoded_doc.len() == utf8_validation.len()
+            );
+        }
+        assert_eq!(&utf8_encoded_doc[..], &utf8_validation[..]);
+    }
+}
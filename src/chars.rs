@@ -0,0 +1,68 @@
+// Whether c may appear literally in a document.
+//
+// xml 1.0, https://www.w3.org/TR/xml/#NT-Char:
+// Char ::= #x9 | #xA | #xD | [#x20-#xD7FF] | [#xE000-#xFFFD] | [#x10000-#x10FFFF]
+//
+// xml 1.1, https://www.w3.org/TR/xml11/#NT-Char, allows every control char except NUL in Char,
+// but the RestrictedChars may only appear as character references:
+// Char ::= [#x1-#xD7FF] | [#xE000-#xFFFD] | [#x10000-#x10FFFF]
+// RestrictedChar ::= [#x1-#x8] | [#xB-#xC] | [#xE-#x1F] | [#x7F-#x84] | [#x86-#x9F]
+//
+// Surrogates can't be chars in rust, so they don't need checking.
+pub fn is_legal_char(c: char, xml11: bool) -> bool {
+    match c {
+        '\u{9}' | '\u{A}' | '\u{D}' => true,
+        '\u{0}'..='\u{1F}' => false,
+        '\u{7F}'..='\u{84}' | '\u{86}'..='\u{9F}' => !xml11,
+        '\u{FFFE}' | '\u{FFFF}' => false,
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod chars_tests {
+    use super::*;
+
+    #[test]
+    fn test_xml10_chars() {
+        for c in &[
+            '\t', '\n', '\r', ' ', 'a', '\u{7F}', '\u{85}', '\u{9F}', '\u{D7FF}',
+        ] {
+            assert!(is_legal_char(*c, false), "{:?}", c);
+        }
+        for c in &['\u{E000}', '\u{FFFD}', '\u{10000}', '\u{10FFFF}'] {
+            assert!(is_legal_char(*c, false), "{:?}", c);
+        }
+        for c in &[
+            '\u{0}', '\u{1}', '\u{8}', '\u{B}', '\u{C}', '\u{E}', '\u{1F}',
+        ] {
+            assert!(!is_legal_char(*c, false), "{:?}", c);
+        }
+        assert!(!is_legal_char('\u{FFFE}', false));
+        assert!(!is_legal_char('\u{FFFF}', false));
+    }
+
+    #[test]
+    fn test_xml11_chars() {
+        for c in &[
+            '\t',
+            '\n',
+            '\r',
+            ' ',
+            'a',
+            '\u{85}',
+            '\u{A0}',
+            '\u{2028}',
+            '\u{10FFFF}',
+        ] {
+            assert!(is_legal_char(*c, true), "{:?}", c);
+        }
+        for c in &[
+            '\u{0}', '\u{1}', '\u{1F}', '\u{7F}', '\u{84}', '\u{86}', '\u{9F}',
+        ] {
+            assert!(!is_legal_char(*c, true), "{:?}", c);
+        }
+        assert!(!is_legal_char('\u{FFFE}', true));
+        assert!(!is_legal_char('\u{FFFF}', true));
+    }
+}
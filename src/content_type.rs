@@ -0,0 +1,121 @@
+/// The encoding that an HTTP or MIME `Content-Type` header value gives a document, following
+/// [RFC 3023](https://tools.ietf.org/html/rfc3023).
+///
+/// That's the `charset` parameter if there is one. Without it, `text/xml` and the other `text/`
+/// xml media types default to "us-ascii" (section 3.1), while `application/xml` and the rest
+/// leave the encoding for the document to decide, so there's no encoding to return (section
+/// 3.2).
+///
+/// ```
+/// use xmlbufrw::content_type_charset;
+///
+/// assert_eq!(
+///     content_type_charset("application/xml; charset=\"Shift_JIS\""),
+///     Some("Shift_JIS".to_string())
+/// );
+/// assert_eq!(content_type_charset("text/xml"), Some("us-ascii".to_string()));
+/// assert_eq!(content_type_charset("application/xml"), None);
+/// ```
+pub fn content_type_charset(content_type: &str) -> Option<String> {
+    if let Some(charset) = charset_parameter(content_type) {
+        return Some(charset);
+    }
+
+    let media_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_lowercase();
+    // text/xml, text/xml-external-parsed-entity and text/*+xml (section 7)
+    if media_type.starts_with("text/")
+        && (media_type == "text/xml"
+            || media_type == "text/xml-external-parsed-entity"
+            || media_type.ends_with("+xml"))
+    {
+        Some("us-ascii".to_string())
+    } else {
+        None
+    }
+}
+
+// Only the charset the header value spells out, not the one its media type defaults to
+pub fn charset_parameter(content_type: &str) -> Option<String> {
+    for parameter in content_type.split(';').skip(1) {
+        let mut name_value = parameter.splitn(2, '=');
+        let name = name_value.next().unwrap_or("").trim();
+        if !name.eq_ignore_ascii_case("charset") {
+            continue;
+        }
+        let charset = unquote(name_value.next().unwrap_or("").trim());
+        if !charset.is_empty() {
+            return Some(charset);
+        }
+    }
+    None
+}
+
+// A parameter value is either a token or a quoted-string, whose backslashes escape the next char
+fn unquote(value: &str) -> String {
+    if !(value.len() >= 2 && value.starts_with('"') && value.ends_with('"')) {
+        return value.to_string();
+    }
+    let mut unquoted = String::with_capacity(value.len());
+    let mut chars = value[1..value.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unquoted.extend(chars.next()),
+            c => unquoted.push(c),
+        }
+    }
+    unquoted
+}
+
+#[cfg(test)]
+mod content_type_tests {
+    use super::*;
+
+    #[test]
+    fn test_charset_parameter() {
+        assert_eq!(
+            content_type_charset("text/xml; charset=utf-8"),
+            Some("utf-8".to_string())
+        );
+        assert_eq!(
+            content_type_charset("application/xml;CHARSET=\"iso-8859-1\""),
+            Some("iso-8859-1".to_string())
+        );
+        assert_eq!(
+            content_type_charset("application/xml; version=1; charset = \"koi\\8-r\" "),
+            Some("koi8-r".to_string())
+        );
+        assert_eq!(
+            content_type_charset("text/html; charset=windows-1252"),
+            Some("windows-1252".to_string())
+        );
+    }
+
+    #[test]
+    fn test_defaults() {
+        assert_eq!(
+            content_type_charset("text/xml"),
+            Some("us-ascii".to_string())
+        );
+        assert_eq!(
+            content_type_charset("Text/XML; charset="),
+            Some("us-ascii".to_string())
+        );
+        assert_eq!(
+            content_type_charset("text/xml-external-parsed-entity"),
+            Some("us-ascii".to_string())
+        );
+        assert_eq!(
+            content_type_charset("text/svg+xml"),
+            Some("us-ascii".to_string())
+        );
+        assert_eq!(content_type_charset("application/xml"), None);
+        assert_eq!(content_type_charset("application/atom+xml"), None);
+        assert_eq!(content_type_charset("text/plain"), None);
+        assert_eq!(content_type_charset(""), None);
+    }
+}
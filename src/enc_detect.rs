@@ -3,44 +3,264 @@ use std::io::Read;
 
 use encoding_rs;
 
-pub fn decoder_helper(decoder: &mut encoding_rs::Decoder, input: &[u8]) -> io::Result<String> {
+use ebcdic;
+use error::{Error, Result};
+use position::LineTracker;
+use xml_decl::XmlDecl;
+
+// offset is where input starts in the document, and preceding is the text decoded before it, for
+// error reporting
+pub fn decoder_helper(
+    decoder: &mut Decoder,
+    input: &[u8],
+    offset: usize,
+    preceding: &str,
+) -> Result<String> {
     let mut decoded = String::with_capacity(input.len() * 4);
 
     let (result, bytes_read) =
-        decoder.decode_to_string_without_replacement(&input, &mut decoded, false);
-    if let encoding_rs::DecoderResult::Malformed(_, _) = result {
-        Err(io::Error::new(
-            io::ErrorKind::Other,
-            format!("Malformed input. {:x?}, position {}.", input, bytes_read),
-        ))
+        decoder.decode_to_string_without_replacement(input, &mut decoded, false);
+    if let encoding_rs::DecoderResult::Malformed(malformed_len, consumed_after) = result {
+        let mut line_tracker = LineTracker::new(false);
+        preceding
+            .chars()
+            .chain(decoded.chars())
+            .for_each(|c| line_tracker.advance(c));
+        Err(Error::MalformedInput {
+            position: line_tracker
+                .position(offset + bytes_read - malformed_len as usize - consumed_after as usize),
+        })
     } else {
         Ok(decoded)
     }
 }
 
+// How much of bytes there is before a utf-8 sequence that's cut off at the end, if there is one
+pub fn utf8_complete_len(bytes: &[u8]) -> usize {
+    // A sequence is at most four bytes long, so the last one starts within the last four
+    for (back, &b) in bytes.iter().rev().take(4).enumerate() {
+        // Continuation bytes are 0b10xxxxxx
+        if b & 0xc0 == 0x80 {
+            continue;
+        }
+        let sequence_len = match b {
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf7 => 4,
+            // Malformed input is left for validation to find
+            _ => 1,
+        };
+        let start = bytes.len() - 1 - back;
+        return if start + sequence_len > bytes.len() {
+            start
+        } else {
+            bytes.len()
+        };
+    }
+    bytes.len()
+}
+
+// Like read_exact, but a short read at the end of the input isn't an error - returns how much of
+// buf was filled
+fn read_available<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Which rule of the
+/// [xml 1.0 specification's section F](https://www.w3.org/TR/xml/#sec-guessing) decided the
+/// input encoding of a document.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DetectionRule {
+    /// F.1: the document starts with a byte order mark
+    ByteOrderMark,
+    /// F.1: the byte pattern of the first characters, `<` or `<?`, gave away a UTF-16 or UTF-32
+    /// encoding
+    FirstCharacters,
+    /// F.1: the encoding declaration named the encoding within the family that the first
+    /// characters of the document belong to
+    EncodingDeclaration,
+    /// F.2: there was neither a byte order mark nor an encoding declaration, or the externally
+    /// suggested encoding was set to override the document, so it was used
+    ExternalInformation,
+    /// There was neither a byte order mark nor an encoding declaration, so the document must be
+    /// utf-8 ([section 4.3.3](https://www.w3.org/TR/xml/#charencoding))
+    Utf8Default,
+}
+
+/// Describes how the input encoding of a document was decided.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DetectionReport {
+    /// The encoding whose byte order mark the document starts with, if any
+    pub bom: Option<String>,
+    /// Whether the document starts with an xml declaration
+    pub has_xml_decl: bool,
+    /// The encoding label from the xml declaration, exactly as it was written
+    pub declared_encoding: Option<String>,
+    /// The parsed xml declaration
+    pub xml_decl: Option<XmlDecl>,
+    /// The encoding suggested by the caller, e.g. from a transport protocol
+    pub suggested_encoding: Option<String>,
+    /// The name of the encoding the document is decoded with
+    pub encoding: String,
+    /// The rule that decided `encoding`
+    pub rule: DetectionRule,
+}
+
+impl DetectionReport {
+    fn decided(
+        mut self,
+        encoding: Encoding,
+        rule: DetectionRule,
+        prebuf: Vec<u8>,
+    ) -> (Encoding, Vec<u8>, DetectionReport) {
+        self.encoding = encoding.get_name();
+        self.rule = rule;
+        (encoding, prebuf, self)
+    }
+}
+
+/// What to do with an encoding suggested by something other than the document, like the charset
+/// of an HTTP `Content-Type` header. See
+/// [xml 1.0 specification's section F.2](https://www.w3.org/TR/xml/#sec-guessing-with-ext-info).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SuggestedEncodingPolicy {
+    /// Use the suggested encoding only when the document has neither a byte order mark nor an
+    /// encoding declaration
+    Fallback,
+    /// Use the suggested encoding in place of the encoding declaration. A byte order mark still
+    /// wins, and the suggested encoding has to fit the first characters of the document.
+    Override,
+    /// Fail with `Error::SuggestedEncodingConflict` when the byte order mark, first characters
+    /// or encoding declaration decide on a different encoding than the suggested one
+    RejectConflict,
+}
+
 // Implements the encoding detection heuristic suggested by
 // https://www.w3.org/TR/xml/#sec-guessing
 pub fn detect_encoding_with_suggestion<R: Read>(
     suggested_encoding: Option<String>,
+    policy: SuggestedEncodingPolicy,
     reader: &mut R,
-) -> io::Result<(Encoding, Vec<u8>)> {
+) -> Result<(Encoding, Vec<u8>, DetectionReport)> {
+    let (encoding, prebuf, report) =
+        detect_encoding_from_document(suggested_encoding.clone(), reader)?;
+    let encoding_name = match suggested_encoding {
+        Some(encoding_name) => encoding_name,
+        None => return Ok((encoding, prebuf, report)),
+    };
+    match policy {
+        SuggestedEncodingPolicy::Fallback => Ok((encoding, prebuf, report)),
+        SuggestedEncodingPolicy::RejectConflict => {
+            if suggestion_agrees(&encoding, report.rule, &encoding_name)? {
+                Ok((encoding, prebuf, report))
+            } else {
+                Err(Error::SuggestedEncodingConflict {
+                    suggested: encoding_name,
+                    detected: report.encoding,
+                })
+            }
+        }
+        SuggestedEncodingPolicy::Override if report.bom.is_some() => Ok((encoding, prebuf, report)),
+        SuggestedEncodingPolicy::Override => {
+            // Without a BOM, the prebuf starts with the same bytes the first guess was made from
+            let (encoding_guess, _) =
+                Encoding::new_from_buffer(&prebuf[..std::cmp::min(4, prebuf.len())])?;
+            if !encoding_guess.encoding_decl_is_compatible(&encoding_name)? {
+                return Err(Error::SuggestedEncodingConflict {
+                    suggested: encoding_name,
+                    detected: encoding_guess.get_name(),
+                });
+            }
+            // UTF-16 and UTF-32 first characters already give the byte order that a compatible
+            // suggestion may leave out
+            let encoding = if encoding_guess.is_definitive() {
+                encoding_guess
+            } else {
+                Encoding::new_from_name(&encoding_name, true)?
+            };
+            Ok(report.decided(encoding, DetectionRule::ExternalInformation, prebuf))
+        }
+    }
+}
+
+// Whether the suggested encoding is the one the document was found to be in. Only an encoding
+// that the suggestion decided on can't disagree with it - a document that falls back on utf-8
+// is as much in utf-8 as one that declares it.
+fn suggestion_agrees(encoding: &Encoding, rule: DetectionRule, suggested: &str) -> Result<bool> {
+    if rule == DetectionRule::ExternalInformation {
+        return Ok(true);
+    }
+    if Encoding::new_from_name(suggested, false)?.get_name() == encoding.get_name() {
+        return Ok(true);
+    }
+    // A byte order mark or the first characters pin down the encoding more exactly than a
+    // family name like "utf-16" does
+    match rule {
+        DetectionRule::ByteOrderMark | DetectionRule::FirstCharacters => {
+            encoding.encoding_decl_is_compatible(suggested)
+        }
+        _ => Ok(false),
+    }
+}
+
+fn detect_encoding_from_document<R: Read>(
+    suggested_encoding: Option<String>,
+    reader: &mut R,
+) -> Result<(Encoding, Vec<u8>, DetectionReport)> {
     let mut prebuf: Vec<u8> = Vec::with_capacity(64);
-    // Check the first four bytes
+    // Check the first four bytes - documents can be shorter than that
     let mut quad = [0; 4];
-    reader.take(quad.len() as u64).read_exact(&mut quad)?;
+    let quad_len = read_available(reader, &mut quad)?;
 
-    let (encoding_guess, bom_bytes) = Encoding::new_from_buffer(&quad[0..4])?;
+    let (encoding_guess, bom_bytes) = Encoding::new_from_buffer(&quad[..quad_len])?;
     // Add all bytes after the bom (if present) to the prebuf
-    prebuf.extend(&quad[bom_bytes..]);
+    prebuf.extend(&quad[bom_bytes..quad_len]);
+
+    let mut report = DetectionReport {
+        bom: if bom_bytes > 0 {
+            Some(encoding_guess.get_name())
+        } else {
+            None
+        },
+        has_xml_decl: false,
+        declared_encoding: None,
+        xml_decl: None,
+        suggested_encoding: suggested_encoding.clone(),
+        encoding: String::new(),
+        rule: DetectionRule::Utf8Default,
+    };
+    // The rule to credit if the guess from the first four bytes stands
+    let guess_rule = if bom_bytes > 0 {
+        DetectionRule::ByteOrderMark
+    } else if encoding_guess.is_definitive() {
+        DetectionRule::FirstCharacters
+    } else if encoding_guess.get_name() == "utf-8" {
+        DetectionRule::Utf8Default
+    } else {
+        // EBCDIC '<?xm' without an encodingdecl
+        DetectionRule::FirstCharacters
+    };
 
     // Extend the prebuf with enough chars to have gotten the xmldecl prefix
     let xml_decl_prefix = "<?xml ";
     let char_width = encoding_guess.get_char_width();
     let xml_decl_prefix_width = xml_decl_prefix.len() * char_width;
     // Buffer for reading a-char-at-a-time until we have enough to see if there's an xmldecl
-    let mut tmp_buf: Vec<u8> = vec![0; xml_decl_prefix_width - prebuf.len()];
-    reader.take(tmp_buf.len() as u64).read_exact(&mut tmp_buf)?;
-    prebuf.extend(&tmp_buf);
+    // If the document ends first, it's too short to have one
+    if quad_len == quad.len() {
+        let mut tmp_buf: Vec<u8> = vec![0; xml_decl_prefix_width - prebuf.len()];
+        let tmp_len = read_available(reader, &mut tmp_buf)?;
+        prebuf.extend(&tmp_buf[..tmp_len]);
+    }
 
     let mut temp_decoder = encoding_guess.get_decoder()?;
 
@@ -48,122 +268,109 @@ pub fn detect_encoding_with_suggestion<R: Read>(
     // display char may consist of more than one utf char, we're going to decode this one step
     // at a time.
     // make an iterator over chunks of char_width size, decode it
-    let has_xml_decl: bool = prebuf
-        .chunks(char_width)
-        .map(|x| decoder_helper(&mut temp_decoder, x))
-        .zip(xml_decl_prefix.chars())
-        .all(|(input_char_str_result, decl_char)| {
-            if let Ok(input_char_str) = input_char_str_result {
-                (char::is_whitespace(decl_char) && input_char_str.chars().all(char::is_whitespace))
-                    || (decl_char.to_string() == input_char_str)
-            } else {
-                false
-            }
-        });
+    let has_xml_decl: bool = prebuf.len() == xml_decl_prefix_width
+        && prebuf
+            .chunks(char_width)
+            .enumerate()
+            .map(|(i, x)| decoder_helper(&mut temp_decoder, x, bom_bytes + i * char_width, ""))
+            .zip(xml_decl_prefix.chars())
+            .all(|(input_char_str_result, decl_char)| {
+                if let Ok(input_char_str) = input_char_str_result {
+                    (char::is_whitespace(decl_char)
+                        && input_char_str.chars().all(char::is_whitespace))
+                        || (decl_char.to_string() == input_char_str)
+                } else {
+                    false
+                }
+            });
 
     // How to resolve suggested encoding with document inferences:
     // https://www.w3.org/TR/xml/#sec-guessing-with-ext-info
     if !has_xml_decl {
         // If there's no xmldecl, but there is a BOM, rely on that
         if encoding_guess.is_definitive() {
-            return Ok((encoding_guess, prebuf));
+            return Ok(report.decided(encoding_guess, guess_rule, prebuf));
         } else if let Some(encoding_name) = suggested_encoding {
             // If there's no xmldecl, and no BOM, fall back on the suggested encoding
             let encoding = Encoding::new_from_name(&encoding_name, true)?;
-            return Ok((encoding, prebuf));
+            return Ok(report.decided(encoding, DetectionRule::ExternalInformation, prebuf));
         } else {
-            // if no xmldecl, no BOM, and no suggested encoding then error
-            return Err(io::Error::new(io::ErrorKind::Other, "Unable to detect input file encoding.  No Byte Order Mark, and no xml declaration."));
+            // if no xmldecl, no BOM, and no suggested encoding then the document must be utf-8
+            // https://www.w3.org/TR/xml/#charencoding
+            return Ok(report.decided(encoding_guess, guess_rule, prebuf));
         }
     }
-    let mut xml_decl = decoder_helper(&mut temp_decoder, &prebuf)?;
+    report.has_xml_decl = true;
+    let mut xml_decl = decoder_helper(&mut temp_decoder, &prebuf, bom_bytes, "")?;
 
     // Now we have to read through until we get to the end of the xmldecl - "?>"
     let mut one_char_buf: Vec<u8> = vec![0; encoding_guess.get_char_width()];
     while !xml_decl.ends_with("?>") {
-        reader
-            .take(one_char_buf.len() as u64)
-            .read_exact(&mut one_char_buf)?;
+        if read_available(reader, &mut one_char_buf)? < one_char_buf.len() {
+            return Err(Error::MalformedXmlDecl(
+                "document ends before the closing '?>'".to_string(),
+            ));
+        }
         prebuf.extend(&one_char_buf);
-        let next_char = decoder_helper(&mut temp_decoder, &one_char_buf)?;
+        let next_char = decoder_helper(
+            &mut temp_decoder,
+            &one_char_buf,
+            bom_bytes + prebuf.len() - one_char_buf.len(),
+            &xml_decl,
+        )?;
         xml_decl.push_str(&next_char);
         // we don't have a full state machine here to detect if we're running through valid
         // xml_decl data, so we're just going to put a hard upper cap at 256 chars - if we've
         // made it this far without finding "?>", we're giving up
         if xml_decl.len() > 256 {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "Input format error: input doesn't appear to be valid xml.",
+            return Err(Error::MalformedXmlDecl(
+                "no closing '?>' within 256 characters".to_string(),
             ));
         }
     }
 
-    let xml_decl_tokens = xml_decl
-        .split_whitespace()
-        .flat_map(|attr| attr.split('='))
-        .filter(|t| !t.is_empty());
-    let mut encoding_tokens = xml_decl_tokens.skip_while(|t| t != &"encoding");
-    if encoding_tokens.next().is_none() {
+    let parsed_decl = XmlDecl::parse(&xml_decl)?;
+    report.xml_decl = Some(parsed_decl.clone());
+    let encoding_name = match parsed_decl.encoding {
+        Some(encoding_name) => encoding_name,
         // No encoding name in xmldecl
-        return Ok((encoding_guess, prebuf));
-    }
-
-    if let Some(encoding_val) = encoding_tokens.next() {
-        let mut encoding_val_iter = encoding_val.chars();
-        let starting_quote = encoding_val_iter.next().ok_or_else(|| {
-            io::Error::new(
-                io::ErrorKind::Other,
-                "Improperly formatted encodingdecl: unquoted value.",
-            )
-        })?;
-        let encoding_name: String = encoding_val_iter
-            .take_while(|c| c != &starting_quote)
-            .collect::<String>();
-
-        // if definitive and xmldecl, error if encodingdecl doesn't match detected encoding
-        // get value between the quotes
-        if encoding_guess.is_definitive() {
-            if !encoding_guess.encoding_decl_is_compatible(&encoding_name)? {
-                return Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    format!(
-                        "Detected input encoding {} is incompatible with declared encoding {}",
-                        encoding_guess.get_name(),
-                        encoding_name
-                    ),
-                ));
-            }
-            return Ok((encoding_guess, prebuf));
-        } else {
-            // if not definitive, and xmldecl, return xmldecl encoding
-            return Ok((encoding_guess, prebuf));
-        }
-    }
+        None => return Ok(report.decided(encoding_guess, guess_rule, prebuf)),
+    };
+    report.declared_encoding = Some(encoding_name.clone());
 
-    if let Some(encoding_name) = suggested_encoding {
-        Ok((Encoding::new_from_name(&encoding_name, false)?, prebuf))
+    // Whether or not the guess is definitive, the encodingdecl has to agree with it
+    if !encoding_guess.encoding_decl_is_compatible(&encoding_name)? {
+        return Err(Error::BomDeclConflict {
+            detected: encoding_guess.get_name(),
+            declared: encoding_name,
+        });
+    }
+    if encoding_guess.is_definitive() {
+        Ok(report.decided(encoding_guess, guess_rule, prebuf))
     } else {
-        Ok((Encoding::new_from_name("utf-8", false)?, prebuf))
+        // if not definitive, and xmldecl, return xmldecl encoding
+        // The guess only tells us the family (ASCII-compatible or EBCDIC) that the document
+        // is in, the encodingdecl says which member of it
+        let encoding = Encoding::new_from_name(&encoding_name, false)?;
+        Ok(report.decided(encoding, DetectionRule::EncodingDeclaration, prebuf))
     }
 }
 
 pub enum Encoding {
-    Ascii(bool),
-    Utf8(bool),
-    Utf16Le(bool),
-    Utf16Be(bool),
-    // These are encodings that we can guess, but for which we don't have a 
-    // decoder, so we won't emit these
-    /*
+    // Everything in the WHATWG Encoding Standard, i.e. everything encoding_rs supports: utf-8,
+    // utf-16le/be, the legacy single-byte encodings and the CJK multi-byte encodings
+    Whatwg(&'static encoding_rs::Encoding, bool),
+    // Not supported by encoding_rs - see Decoder
     Utf32Le(bool),
     Utf32Be(bool),
-    UtfEbcdic(bool),
-    EbcdicCpUs(bool),
-    */
+    Ebcdic(&'static ebcdic::CodePage, bool),
 }
 
 impl Encoding {
-    pub fn new_from_buffer(buf: &[u8]) -> io::Result<(Self, usize)> {
+    pub fn new_from_buffer(buf: &[u8]) -> Result<(Self, usize)> {
+        if buf.len() < 4 {
+            return Self::new_from_short_buffer(buf);
+        }
         match buf[0..4] {
             // Byte Order Mark test
             // UTF-8
@@ -176,18 +383,20 @@ impl Encoding {
             [0xFE, 0xFF, _po3, _po4] if _po3 == 0x00 && _po4 != 0x00 => {
                 Ok((Self::new_from_name("utf-16be", true)?, 2))
             }
-            /*
             // UCS-4, little endian (4321 order)
             [0xFF, 0xFE, 0x00, 0x00] => Ok((Self::new_from_name("utf-32le", true)?, 4)),
             // UCS-4, big endian (1234 order)
             [0x00, 0x00, 0xFE, 0xFF] => Ok((Self::new_from_name("utf-32be", true)?, 4)),
             // UCS-4, unusual octet order (2143 order)
-            [0x00, 0x00, 0xFF, 0xFE] => Err(io::Error::new(io::ErrorKind::Other, "Unsupported file encoding, \"UCS-4 unusual octet order (2143 order)\"")),
+            [0x00, 0x00, 0xFF, 0xFE] => Err(Error::UnsupportedEncoding(
+                "UCS-4 unusual octet order (2143 order)".to_string(),
+            )),
             // UCS-4, little endian (3412 order)
-            [0xFE, 0xFF, 0x00, 0x00] => Err(io::Error::new(io::ErrorKind::Other, "Unsupported file encoding, \"UCS-4 unusual octet order (3412 order)\"")),
-            // UTF-EBCDIC
-            [0xDD, 0x73, 0x66, 0x73] => Ok((Self::new_from_name("utf-ebcdic", true)?, 4)),
-            */
+            [0xFE, 0xFF, 0x00, 0x00] => Err(Error::UnsupportedEncoding(
+                "UCS-4 unusual octet order (3412 order)".to_string(),
+            )),
+            // UTF-EBCDIC, which is deliberately unsupported - see ebcdic
+            [0xDD, 0x73, 0x66, 0x73] => Err(Error::UnsupportedEncoding("UTF-EBCDIC".to_string())),
 
             // xmldecl char-width/endianness test
             // UTF-8, ISO 646, ASCII, ISO 8859, etc '<?xm'
@@ -197,26 +406,25 @@ impl Encoding {
             [0x3C, 0x00, 0x3F, 0x00] => Ok((Self::new_from_name("utf-16le", true)?, 0)),
             // UTF-16, big-endian '<?'
             [0x00, 0x3C, 0x00, 0x3F] => Ok((Self::new_from_name("utf-16be", true)?, 0)),
-            /*
             // UCS-4, little endian (4321 order) '<'
             [0x3C, 0x00, 0x00, 0x00] => Ok((Self::new_from_name("utf-32le", true)?, 0)),
             // UCS-4, big endian (1234 order) '<'
             [0x00, 0x00, 0x00, 0x3C] => Ok((Self::new_from_name("utf-32be", true)?, 0)),
             // UCS-4, unusual octet order (2143 order) '<'
-            [0x00, 0x00, 0x3C, 0x00] => Err(io::Error::new(io::ErrorKind::Other, "Unsupported file encoding, \"UCS-4 unusual octet order (2143 order)\"")),
+            [0x00, 0x00, 0x3C, 0x00] => Err(Error::UnsupportedEncoding(
+                "UCS-4 unusual octet order (2143 order)".to_string(),
+            )),
             // UCS-4, little endian (3412 order) '<'
-            [0x00, 0x3C, 0x00, 0x00] => Err(io::Error::new(io::ErrorKind::Other, "Unsupported file encoding, \"UCS-4 unusual octet order (3412 order)\"")),
+            [0x00, 0x3C, 0x00, 0x00] => Err(Error::UnsupportedEncoding(
+                "UCS-4 unusual octet order (3412 order)".to_string(),
+            )),
             // Some flavor of EBCDIC '<?xm'
             // encodingDecl required
             [0x4C, 0x6F, 0xA7, 0x94] => Ok((Self::new_from_name("ebcdic-cp-us", false)?, 0)),
-            */
 
             // Any remaining multibyte encodings are unsupported
             [0x00, _, _, _] | [_, 0x00, _, _] | [_, _, 0x00, _] | [_, _, _, 0x00] => {
-                Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    "Missing BOM and no xml declaration, or Unsupported multi-byte file encoding.",
-                ))
+                Err(Error::NoBomNoDecl)
             }
             // No BOM, document doesn't immediately start with xml declaration, but it appears to
             // be a single-byte encoding, so we'll assume utf-8 and hope for the best
@@ -224,92 +432,126 @@ impl Encoding {
         }
     }
 
-    pub fn new_from_name(name: &str, is_definitive: bool) -> io::Result<Self> {
-        if let Some(encoding) = encoding_rs::Encoding::for_label_no_replacement(name.as_bytes()) {
-            match encoding.name().to_lowercase().as_str() {
-                "ascii" => Ok(Encoding::Ascii(is_definitive)),
-                "utf-8" => Ok(Encoding::Utf8(is_definitive)),
-                "utf-16le" => Ok(Encoding::Utf16Le(is_definitive)),
-                "utf-16be" => Ok(Encoding::Utf16Be(is_definitive)),
-                enc => Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    format!("Unsupported encoding requested: {}", enc),
-                )),
+    // A document of fewer than four bytes is too short for a UCS-4 BOM, the '<' and '<?'
+    // patterns, or an xml declaration, so only the shorter BOMs can tell us anything
+    fn new_from_short_buffer(buf: &[u8]) -> Result<(Self, usize)> {
+        match buf {
+            [0xEF, 0xBB, 0xBF] => Ok((Self::new_from_name("utf-8", true)?, 3)),
+            [0xFF, 0xFE, ..] => Ok((Self::new_from_name("utf-16le", true)?, 2)),
+            [0xFE, 0xFF, ..] => Ok((Self::new_from_name("utf-16be", true)?, 2)),
+            _ if buf.contains(&0x00) => Err(Error::NoBomNoDecl),
+            _ => Ok((Self::new_from_name("utf-8", false)?, 0)),
+        }
+    }
+
+    pub fn new_from_name(name: &str, is_definitive: bool) -> Result<Self> {
+        // encoding_rs doesn't know about UTF-32
+        match name.trim().to_lowercase().as_str() {
+            "utf-32le" => return Ok(Encoding::Utf32Le(is_definitive)),
+            // Without a BOM, UTF-32 is big-endian
+            "utf-32" | "utf-32be" | "ucs-4" | "iso-10646-ucs-4" => {
+                return Ok(Encoding::Utf32Be(is_definitive))
             }
+            _ => {}
+        }
+        if let Some(code_page) = ebcdic::for_label(name) {
+            return Ok(Encoding::Ebcdic(code_page, is_definitive));
+        }
+        if let Some(encoding) = encoding_rs::Encoding::for_label_no_replacement(name.as_bytes()) {
+            Ok(Encoding::Whatwg(encoding, is_definitive))
         } else {
-            Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("Unsupported encoding requested: {}", name),
-            ))
+            Err(Error::UnsupportedEncoding(name.to_string()))
         }
     }
 
-    pub fn get_decoder(&self) -> io::Result<encoding_rs::Decoder> {
-        encoding_rs::Encoding::for_label_no_replacement(&self.get_name().as_bytes())
-            .ok_or_else(|| {
-                io::Error::new(
-                    io::ErrorKind::Other,
-                    format!("Unrecognized input encoding name: {}", self.get_name()),
-                )
-            }).map(|enc| enc.new_decoder_without_bom_handling())
+    pub fn get_decoder(&self) -> Result<Decoder> {
+        match self {
+            Encoding::Whatwg(encoding, _) => Ok(Decoder::EncodingRs(
+                encoding.new_decoder_without_bom_handling(),
+            )),
+            Encoding::Utf32Le(_) => Ok(Decoder::Utf32(Utf32Decoder::new(false))),
+            Encoding::Utf32Be(_) => Ok(Decoder::Utf32(Utf32Decoder::new(true))),
+            Encoding::Ebcdic(code_page, _) => Ok(Decoder::Ebcdic(code_page)),
+        }
     }
 
     pub fn get_name(&self) -> String {
         match self {
-            Encoding::Ascii(_) => "ascii".to_string(),
-            Encoding::Utf8(_) => "utf-8".to_string(),
-            Encoding::Utf16Le(_) => "utf-16le".to_string(),
-            Encoding::Utf16Be(_) => "utf-16be".to_string(),
-            /*
+            Encoding::Whatwg(encoding, _) => encoding.name().to_lowercase(),
             Encoding::Utf32Le(_) => "utf-32le".to_string(),
             Encoding::Utf32Be(_) => "utf-32be".to_string(),
-            Encoding::UtfEbcdic(_) => "utf-ebcdic".to_string(),
-            Encoding::EbcdicCpUs(_) => "ebcdic-cp-us".to_string(),
-            */
+            Encoding::Ebcdic(code_page, _) => code_page.name().to_string(),
         }
     }
 
     pub fn get_char_width(&self) -> usize {
         match self {
-            Encoding::Ascii(_) => 1,
-            Encoding::Utf8(_) => 1,
-            Encoding::Utf16Le(_) => 2,
-            Encoding::Utf16Be(_) => 2,
-            /*
+            Encoding::Whatwg(encoding, _)
+                if *encoding == encoding_rs::UTF_16LE || *encoding == encoding_rs::UTF_16BE =>
+            {
+                2
+            }
+            // The multi-byte CJK encodings still encode ASCII (and so the xmldecl) as one byte
+            Encoding::Whatwg(_, _) => 1,
             Encoding::Utf32Le(_) => 4,
             Encoding::Utf32Be(_) => 4,
-            Encoding::UtfEbcdic(_) => 1,
-            Encoding::EbcdicCpUs(_) => 1,
-            */
+            Encoding::Ebcdic(_, _) => 1,
+        }
+    }
+
+    pub fn get_bom(&self) -> Option<&'static [u8]> {
+        match self {
+            Encoding::Whatwg(encoding, _) if *encoding == encoding_rs::UTF_8 => {
+                Some(&[0xEF, 0xBB, 0xBF])
+            }
+            Encoding::Whatwg(encoding, _) if *encoding == encoding_rs::UTF_16LE => {
+                Some(&[0xFF, 0xFE])
+            }
+            Encoding::Whatwg(encoding, _) if *encoding == encoding_rs::UTF_16BE => {
+                Some(&[0xFE, 0xFF])
+            }
+            Encoding::Whatwg(_, _) => None,
+            Encoding::Utf32Le(_) => Some(&[0xFF, 0xFE, 0x00, 0x00]),
+            Encoding::Utf32Be(_) => Some(&[0x00, 0x00, 0xFE, 0xFF]),
+            Encoding::Ebcdic(_, _) => None,
         }
     }
 
     pub fn is_definitive(&self) -> bool {
         match self {
-            Encoding::Ascii(is_definitive)
-            | Encoding::Utf8(is_definitive)
-            | Encoding::Utf16Le(is_definitive)
-            | Encoding::Utf16Be(is_definitive) => *is_definitive,
-            /*
-            Encoding::Utf32Le(is_definitive) |
-            Encoding::Utf32Be(is_definitive) |
-            Encoding::UtfEbcdic(is_definitive) |
-            Encoding::EbcdicCpUs(is_definitive) => *is_definitive,
-            */
+            Encoding::Whatwg(_, is_definitive)
+            | Encoding::Utf32Le(is_definitive)
+            | Encoding::Utf32Be(is_definitive)
+            | Encoding::Ebcdic(_, is_definitive) => *is_definitive,
         }
     }
 
-    pub fn encoding_decl_is_compatible(&self, encoding_decl_name: &str) -> io::Result<bool> {
+    pub fn encoding_decl_is_compatible(&self, encoding_decl_name: &str) -> Result<bool> {
+        // encoding_rs doesn't know about UTF-32, and maps the endian-neutral "utf-16" label to
+        // utf-16le, so the UTF family names are checked here
+        let self_name = self.get_name();
+        match encoding_decl_name.trim().to_lowercase().as_str() {
+            "utf-16" => return Ok(self_name.starts_with("utf-16")),
+            "utf-32" | "ucs-4" | "iso-10646-ucs-4" => return Ok(self_name.starts_with("utf-32")),
+            decl_name @ "utf-32le" | decl_name @ "utf-32be" => return Ok(self_name == decl_name),
+            _ => {}
+        }
+        // The EBCDIC code pages agree on every character an xml declaration can contain, so
+        // they're interchangeable for the purposes of detection
+        if let Encoding::Ebcdic(_, _) = self {
+            if encoding_decl_name.trim().eq_ignore_ascii_case("utf-ebcdic") {
+                return Err(Error::UnsupportedEncoding(encoding_decl_name.to_string()));
+            }
+            return Ok(ebcdic::for_label(encoding_decl_name).is_some());
+        }
+        if ebcdic::for_label(encoding_decl_name).is_some() {
+            return Ok(false);
+        }
+
         let other_decoder =
             encoding_rs::Encoding::for_label_no_replacement(encoding_decl_name.as_bytes())
-                .ok_or_else(|| {
-                    io::Error::new(
-                        io::ErrorKind::Other,
-                        format!("Unrecognized input encoding name: {}", encoding_decl_name),
-                    )
-                })?;
-
-        let self_name = self.get_name().to_lowercase();
+                .ok_or_else(|| Error::UnsupportedEncoding(encoding_decl_name.to_string()))?;
+
         let other_name = other_decoder.name().to_lowercase();
 
         // This takes care of all UTF-16 cases
@@ -323,50 +565,247 @@ impl Encoding {
             return Ok(false);
         }
 
-        // non-definitive encodings are ascii, utf-8, and ebcdic-cp-us
-        // and non-definitive detection of ascii and utf-8 are basically
-        // interchangeable
-        // Basically, the two are compatible if we're ascii or utf-8, and they're any of the
-        // supported singlebyte encodings here:
-        // https://docs.rs/encoding_rs/0.8.13/src/encoding_rs/lib.rs.html
-        // look for LABELS_SORTED
-        if self_name == "utf-8" || self_name == "ascii" {
-            let compat = match other_name.as_str() {
-                "ascii" => true,
-                "utf-8" => true,
-                "ibm866" => true,
-                "iso-8859-1" => true,
-                "iso-8859-2" => true,
-                "iso-8859-3" => true,
-                "iso-8859-4" => true,
-                "iso-8859-5" => true,
-                "iso-8859-6" => true,
-                "iso-8859-7" => true,
-                "iso-8859-8" => true,
-                "iso-8859-10" => true,
-                "iso-8859-13" => true,
-                "iso-8859-14" => true,
-                "iso-8859-15" => true,
-                "iso-8859-16" => true,
-                "koi8-r" => true,
-                "koi8-u" => true,
-                "mac-roman" => true,
-                "windows-874" => true,
-                "windows-1250" => true,
-                "windows-1251" => true,
-                "windows-1252" => true,
-                "windows-1253" => true,
-                "windows-1254" => true,
-                "windows-1255" => true,
-                "windows-1256" => true,
-                "windows-1257" => true,
-                "windows-1258" => true,
-                "mac-cyrillic" => true,
-                _ => false,
-            };
-            Ok(compat)
+        // non-definitive encodings are utf-8, and ebcdic-cp-us
+        // Any encoding that encodes the xmldecl the same way ascii does is compatible with a
+        // non-definitive utf-8 detection. ISO-2022-JP isn't ASCII-compatible by encoding_rs'
+        // definition (it has escape sequences), but it starts out in ASCII mode.
+        if self_name == "utf-8" {
+            Ok(other_decoder.is_ascii_compatible() || other_decoder == encoding_rs::ISO_2022_JP)
         } else {
-            Err(io::Error::new(io::ErrorKind::Other, format!("Unable to determine compatibility of detected encoding {} and declared encoding {}", self_name, other_name)))
+            Err(Error::BomDeclConflict {
+                detected: self_name,
+                declared: encoding_decl_name.to_string(),
+            })
+        }
+    }
+}
+
+// encoding_rs has no UTF-32 support, so decoding goes through this adapter, which mirrors the
+// parts of encoding_rs::Decoder's interface that we use
+pub enum Decoder {
+    EncodingRs(encoding_rs::Decoder),
+    Utf32(Utf32Decoder),
+    Ebcdic(&'static ebcdic::CodePage),
+}
+
+impl Decoder {
+    pub fn max_utf8_buffer_length_without_replacement(&self, byte_length: usize) -> Option<usize> {
+        match self {
+            Decoder::EncodingRs(decoder) => {
+                decoder.max_utf8_buffer_length_without_replacement(byte_length)
+            }
+            Decoder::Utf32(decoder) => decoder.max_utf8_buffer_length(byte_length),
+            // Every byte decodes to a code point below U+0100
+            Decoder::Ebcdic(_) => byte_length.checked_mul(2),
+        }
+    }
+
+    pub fn decode_to_string_without_replacement(
+        &mut self,
+        src: &[u8],
+        dst: &mut String,
+        last: bool,
+    ) -> (encoding_rs::DecoderResult, usize) {
+        match self {
+            Decoder::EncodingRs(decoder) => {
+                decoder.decode_to_string_without_replacement(src, dst, last)
+            }
+            Decoder::Utf32(decoder) => decoder.decode_to_string(src, dst, last),
+            Decoder::Ebcdic(code_page) => code_page.decode_to_string(src, dst),
+        }
+    }
+
+    // Decoding utf-8 only validates it, so utf-8 input can be passed through without a decoder
+    pub fn is_utf8(&self) -> bool {
+        match self {
+            Decoder::EncodingRs(decoder) => decoder.encoding() == encoding_rs::UTF_8,
+            _ => false,
+        }
+    }
+
+    // How many bytes of input a char was decoded from, as far as the char alone tells
+    pub fn source_len(&self) -> SourceLen {
+        match self {
+            Decoder::EncodingRs(decoder) => {
+                let encoding = decoder.encoding();
+                if encoding == encoding_rs::UTF_8 {
+                    SourceLen::Utf8
+                } else if encoding == encoding_rs::UTF_16LE || encoding == encoding_rs::UTF_16BE {
+                    SourceLen::Utf16
+                } else if encoding.is_single_byte() {
+                    SourceLen::SingleByte
+                } else {
+                    SourceLen::MultiByte
+                }
+            }
+            Decoder::Utf32(_) => SourceLen::Utf32,
+            Decoder::Ebcdic(_) => SourceLen::SingleByte,
+        }
+    }
+
+    // The multi-byte CJK encodings are the ones where a char doesn't say how long its encoded
+    // form was, so their input needs scanning
+    pub fn sequence_scanner(&self) -> Option<SequenceScanner> {
+        match self.source_len() {
+            SourceLen::MultiByte => match self {
+                Decoder::EncodingRs(decoder) => Some(SequenceScanner {
+                    encoding: decoder.encoding(),
+                    double_byte: false,
+                }),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceLen {
+    Utf8,
+    Utf16,
+    Utf32,
+    SingleByte,
+    // Ascii is a byte and most other chars are two, but a SequenceScanner has to say for sure
+    MultiByte,
+}
+
+impl SourceLen {
+    pub fn of(self, c: char) -> usize {
+        match self {
+            SourceLen::Utf8 => c.len_utf8(),
+            SourceLen::Utf16 => c.len_utf16() * 2,
+            SourceLen::Utf32 => 4,
+            SourceLen::SingleByte => 1,
+            SourceLen::MultiByte if c.is_ascii() => 1,
+            SourceLen::MultiByte => 2,
+        }
+    }
+}
+
+// Finds where each char starts in input to one of the multi-byte CJK encodings by looking at
+// lead bytes, so that the input can still be decoded in bulk. It only has to agree with the
+// decoder on well-formed input: malformed input ends a call to the decoder, and scanning starts
+// over after it.
+pub struct SequenceScanner {
+    encoding: &'static encoding_rs::Encoding,
+    // ISO-2022-JP has switched to JIS X 0208, where every char is two bytes
+    double_byte: bool,
+}
+
+impl SequenceScanner {
+    // How long the sequence that bytes starts with is, and how many chars it decodes to: a few
+    // Big5 sequences decode to two, and ISO-2022-JP escape sequences to none. None if bytes ends
+    // partway through the sequence.
+    pub fn next_sequence(&mut self, bytes: &[u8]) -> Option<(usize, usize)> {
+        let lead = *bytes.first()?;
+        let (len, chars) = if self.encoding == encoding_rs::ISO_2022_JP {
+            if lead == 0x1B {
+                (3, 0)
+            } else if self.double_byte {
+                (2, 1)
+            } else {
+                (1, 1)
+            }
+        } else if lead < 0x80 {
+            (1, 1)
+        } else if self.encoding == encoding_rs::SHIFT_JIS {
+            match lead {
+                0x81..=0x9F | 0xE0..=0xFC => (2, 1),
+                _ => (1, 1),
+            }
+        } else if self.encoding == encoding_rs::EUC_JP {
+            match lead {
+                0x8F => (3, 1),
+                _ => (2, 1),
+            }
+        } else if self.encoding == encoding_rs::BIG5 {
+            // These decode to a letter followed by a combining mark
+            match (lead, *bytes.get(1)?) {
+                (0x88, 0x62) | (0x88, 0x64) | (0x88, 0xA3) | (0x88, 0xA5) => (2, 2),
+                _ => (2, 1),
+            }
+        } else if self.encoding == encoding_rs::GBK || self.encoding == encoding_rs::GB18030 {
+            if lead == 0x80 {
+                (1, 1)
+            } else if let 0x30..=0x39 = *bytes.get(1)? {
+                (4, 1)
+            } else {
+                (2, 1)
+            }
+        } else {
+            // EUC-KR
+            (2, 1)
+        };
+        if bytes.len() < len {
+            return None;
+        }
+        if chars == 0 {
+            // ESC ( B, ESC ( J and ESC ( I switch to a single-byte mode, and ESC $ @ and
+            // ESC $ B to JIS X 0208
+            self.double_byte = bytes[1] == b'$';
+        }
+        Some((len, chars))
+    }
+}
+
+pub struct Utf32Decoder {
+    big_endian: bool,
+    // A code unit split across calls to decode
+    partial: [u8; 4],
+    partial_len: usize,
+}
+
+impl Utf32Decoder {
+    pub fn new(big_endian: bool) -> Self {
+        Utf32Decoder {
+            big_endian,
+            partial: [0; 4],
+            partial_len: 0,
+        }
+    }
+
+    pub fn max_utf8_buffer_length(&self, byte_length: usize) -> Option<usize> {
+        // Every 4 bytes of input become at most 4 bytes of utf-8
+        byte_length.checked_add(self.partial_len)
+    }
+
+    pub fn decode_to_string(
+        &mut self,
+        src: &[u8],
+        dst: &mut String,
+        last: bool,
+    ) -> (encoding_rs::DecoderResult, usize) {
+        let mut bytes_read = 0;
+        loop {
+            while self.partial_len < 4 && bytes_read < src.len() {
+                self.partial[self.partial_len] = src[bytes_read];
+                self.partial_len += 1;
+                bytes_read += 1;
+            }
+            if self.partial_len < 4 {
+                if last && self.partial_len > 0 {
+                    let malformed_len = self.partial_len as u8;
+                    self.partial_len = 0;
+                    return (
+                        encoding_rs::DecoderResult::Malformed(malformed_len, 0),
+                        bytes_read,
+                    );
+                }
+                return (encoding_rs::DecoderResult::InputEmpty, bytes_read);
+            }
+            if dst.capacity() - dst.len() < 4 {
+                return (encoding_rs::DecoderResult::OutputFull, bytes_read);
+            }
+            self.partial_len = 0;
+            let code_point = if self.big_endian {
+                u32::from_be_bytes(self.partial)
+            } else {
+                u32::from_le_bytes(self.partial)
+            };
+            match std::char::from_u32(code_point) {
+                Some(c) => dst.push(c),
+                None => return (encoding_rs::DecoderResult::Malformed(4, 0), bytes_read),
+            }
         }
     }
 }
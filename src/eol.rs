@@ -0,0 +1,75 @@
+// End-of-line handling, https://www.w3.org/TR/xml/#sec-line-ends and
+// https://www.w3.org/TR/xml11/#sec-line-ends
+//
+// Text arrives a buffer at a time, so a "\r" that ends one buffer is remembered, and a "\n"
+// (or, for xml 1.1, a NEL) that starts the next one is dropped.
+#[derive(Clone)]
+pub struct EolNormalizer {
+    xml11: bool,
+    after_cr: bool,
+}
+
+impl EolNormalizer {
+    pub fn new(xml11: bool) -> Self {
+        EolNormalizer {
+            xml11,
+            after_cr: false,
+        }
+    }
+
+    // What c becomes in the normalized text - None if it's the second half of a line end pair
+    pub fn normalize_char(&mut self, c: char) -> Option<char> {
+        let after_cr = self.after_cr;
+        self.after_cr = c == '\r';
+        match c {
+            // The second half of a "\r\n" or (xml 1.1) "\r\u{85}" pair
+            '\n' if after_cr => None,
+            '\u{85}' if after_cr && self.xml11 => None,
+            '\r' => Some('\n'),
+            '\u{85}' | '\u{2028}' if self.xml11 => Some('\n'),
+            c => Some(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod eol_tests {
+    use super::*;
+
+    fn normalize_chunks(xml11: bool, chunks: &[&str]) -> String {
+        let mut normalizer = EolNormalizer::new(xml11);
+        let mut normalized = String::new();
+        for chunk in chunks {
+            normalized.extend(chunk.chars().filter_map(|c| normalizer.normalize_char(c)));
+        }
+        normalized
+    }
+
+    #[test]
+    fn test_xml10() {
+        assert_eq!(normalize_chunks(false, &["a\r\nb\rc\nd"]), "a\nb\nc\nd");
+        assert_eq!(normalize_chunks(false, &["\r\r\n\n"]), "\n\n\n");
+        // NEL and LS are only line ends in xml 1.1
+        assert_eq!(
+            normalize_chunks(false, &["a\r\u{85}b\u{2028}c"]),
+            "a\n\u{85}b\u{2028}c"
+        );
+    }
+
+    #[test]
+    fn test_xml11() {
+        assert_eq!(
+            normalize_chunks(true, &["a\r\nb\r\u{85}c\u{85}d\u{2028}e\rf"]),
+            "a\nb\nc\nd\ne\nf"
+        );
+        assert_eq!(normalize_chunks(true, &["\u{85}\r\u{2028}"]), "\n\n\n");
+    }
+
+    #[test]
+    fn test_split_pairs() {
+        assert_eq!(normalize_chunks(false, &["a\r", "\nb"]), "a\nb");
+        assert_eq!(normalize_chunks(false, &["a\r", "", "\nb"]), "a\nb");
+        assert_eq!(normalize_chunks(false, &["a\r", "b", "\nc"]), "a\nb\nc");
+        assert_eq!(normalize_chunks(true, &["a\r", "\u{85}b"]), "a\nb");
+    }
+}
@@ -0,0 +1,147 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::result;
+
+use position::Position;
+
+/// The ways detecting and decoding the input encoding, or encoding the output, can fail.
+#[derive(Debug)]
+pub enum Error {
+    /// Reading from the wrapped reader, or writing to the wrapped writer, failed
+    Io(io::Error),
+    /// The document has neither a byte order mark nor an xml declaration, and its first bytes
+    /// don't look like any encoding the document could be in
+    NoBomNoDecl,
+    /// The document is in, or declares, an encoding that isn't supported
+    UnsupportedEncoding(String),
+    /// The encoding detected from the byte order mark (or from the first characters of the
+    /// document) contradicts the one named by the encoding declaration
+    BomDeclConflict { detected: String, declared: String },
+    /// The encoding suggested from outside the document contradicts the one the document is in,
+    /// according to its byte order mark, first characters or encoding declaration
+    SuggestedEncodingConflict { suggested: String, detected: String },
+    /// The xml declaration couldn't be parsed
+    MalformedXmlDecl(String),
+    /// The input isn't valid in its encoding, starting at `position`
+    MalformedInput { position: Position },
+    /// The document contains a char that xml doesn't allow
+    IllegalChar { c: char, position: Position },
+    /// The document is longer than the size limit of `limit` bytes
+    SizeLimitExceeded { limit: usize },
+    /// The writer was asked for a byte order mark after something else had been written
+    MisplacedBom,
+    /// The writer was asked for an xml declaration after document content, or a second one
+    MisplacedXmlDecl,
+    /// The output encoding doesn't have a byte order mark
+    NoBom(String),
+    /// The xml declaration in the written content names a different encoding than the output
+    /// encoding
+    DeclOutputConflict { declared: String, output: String },
+    /// The output encoding can't be detected without an encoding declaration, and none was
+    /// written
+    EncodingDeclRequired(String),
+    /// The output encoding can't be detected without a byte order mark or an xml declaration,
+    /// and neither was written
+    BomOrDeclRequired(String),
+    /// The output encoding can't represent `c`, and it's somewhere a character reference isn't
+    /// allowed, e.g. a name or a comment
+    UnencodableChar {
+        c: char,
+        encoding: String,
+        context: &'static str,
+    },
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => e.fmt(f),
+            Error::NoBomNoDecl => write!(
+                f,
+                "Missing BOM and no xml declaration, or unsupported multi-byte file encoding"
+            ),
+            Error::UnsupportedEncoding(name) => write!(f, "Unsupported encoding: {}", name),
+            Error::BomDeclConflict { detected, declared } => write!(
+                f,
+                "Detected input encoding {} is incompatible with declared encoding {}",
+                detected, declared
+            ),
+            Error::SuggestedEncodingConflict {
+                suggested,
+                detected,
+            } => write!(
+                f,
+                "Suggested encoding {} is incompatible with detected input encoding {}",
+                suggested, detected
+            ),
+            Error::MalformedXmlDecl(reason) => write!(f, "Malformed xml declaration: {}", reason),
+            Error::MalformedInput { position } => write!(f, "Malformed input at {}", position),
+            Error::IllegalChar { c, position } => write!(f, "Illegal char {:?} at {}", c, position),
+            Error::SizeLimitExceeded { limit } => {
+                write!(f, "Document is longer than the limit of {} bytes", limit)
+            }
+            Error::MisplacedBom => write!(
+                f,
+                "Byte order mark must be the first thing written to the document"
+            ),
+            Error::MisplacedXmlDecl => write!(
+                f,
+                "Xml declaration must precede all document content, and appear only once"
+            ),
+            Error::NoBom(name) => write!(f, "Output encoding {} has no byte order mark", name),
+            Error::DeclOutputConflict { declared, output } => write!(
+                f,
+                "Declared encoding {} is incompatible with output encoding {}",
+                declared, output
+            ),
+            Error::EncodingDeclRequired(name) => write!(
+                f,
+                "Output encoding {} requires an encoding declaration",
+                name
+            ),
+            Error::BomOrDeclRequired(name) => write!(
+                f,
+                "Output encoding {} requires a byte order mark or an xml declaration",
+                name
+            ),
+            Error::UnencodableChar {
+                c,
+                encoding,
+                context,
+            } => write!(
+                f,
+                "Char {:?} cannot be represented in {}, and character references are not \
+                 allowed in {}",
+                c, encoding, context
+            ),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+// Read callers get the Error back through io::Error::get_ref/into_inner
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
@@ -12,8 +12,33 @@
 ///
 /// It is an error for a document to be in a non-UTF/UCS encoding and lack an encoding declaration.
 extern crate encoding_rs;
-extern crate encodingbufrw;
+#[cfg(test)]
+#[cfg(feature = "async")]
+extern crate futures;
+#[cfg(feature = "async")]
+extern crate futures_io;
 
+#[cfg(feature = "async")]
+pub mod async_reader;
+mod chars;
+mod content_type;
+mod ebcdic;
 mod enc_detect;
+mod eol;
+mod error;
+mod offset_map;
+mod position;
+pub mod push_decoder;
 pub mod reader;
-// TODO: pub mod writer
+pub mod slice;
+pub mod writer;
+mod xml_decl;
+
+pub use content_type::content_type_charset;
+pub use enc_detect::{DetectionReport, DetectionRule, SuggestedEncodingPolicy};
+pub use error::{Error, Result};
+pub use position::Position;
+pub use xml_decl::XmlDecl;
+
+/// How many bytes the reader and the writer buffer at a time, unless they're given a capacity.
+pub const DEFAULT_BUF_SIZE: usize = 8 * 1024;
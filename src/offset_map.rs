@@ -0,0 +1,110 @@
+use std::ops::Range;
+
+// Maps offsets into the decoded utf-8 output back to the source bytes they were decoded from.
+//
+// Keeping the source offset of every char would cost more memory than the output itself, so only
+// every interval-th char is recorded. A decoded range maps to the source range between the
+// checkpoints on either side of it, which the caller can decode again to narrow down.
+pub struct OffsetMap {
+    interval: usize,
+    // (decoded offset, source offset) of every interval-th char of the output
+    checkpoints: Vec<(usize, usize)>,
+    // Chars until the next checkpoint
+    countdown: usize,
+    decoded_len: usize,
+}
+
+impl OffsetMap {
+    pub fn new(interval: usize) -> Self {
+        OffsetMap {
+            interval: std::cmp::max(interval, 1),
+            checkpoints: Vec::new(),
+            countdown: 0,
+            decoded_len: 0,
+        }
+    }
+
+    // c is the next char of the output, and starts source_offset bytes into the document
+    pub fn advance(&mut self, c: char, source_offset: usize) {
+        if self.countdown == 0 {
+            self.checkpoints.push((self.decoded_len, source_offset));
+            self.countdown = self.interval;
+        }
+        self.countdown -= 1;
+        self.decoded_len += c.len_utf8();
+    }
+
+    // The source bytes that decoded came from. source_end is where the next char to be decoded
+    // starts in the document.
+    pub fn source_range(&self, decoded: Range<usize>, source_end: usize) -> Option<Range<usize>> {
+        if decoded.start > decoded.end || decoded.end > self.decoded_len {
+            return None;
+        }
+        let start = if decoded.start == self.decoded_len {
+            source_end
+        } else {
+            match self
+                .checkpoints
+                .binary_search_by_key(&decoded.start, |&(decoded_offset, _)| decoded_offset)
+            {
+                Ok(i) => self.checkpoints[i].1,
+                Err(0) => return None,
+                Err(i) => self.checkpoints[i - 1].1,
+            }
+        };
+        let end = match self
+            .checkpoints
+            .binary_search_by_key(&decoded.end, |&(decoded_offset, _)| decoded_offset)
+        {
+            Ok(i) => self.checkpoints[i].1,
+            Err(i) => self
+                .checkpoints
+                .get(i)
+                .map_or(source_end, |&(_, source_offset)| source_offset),
+        };
+        Some(start..end)
+    }
+}
+
+#[cfg(test)]
+mod offset_map_tests {
+    use super::*;
+
+    // Every char of "a\u{e9}\u{65e5}b" is two bytes in the source
+    fn offset_map(interval: usize) -> OffsetMap {
+        let mut offset_map = OffsetMap::new(interval);
+        for (i, c) in "a\u{e9}\u{65e5}b".chars().enumerate() {
+            offset_map.advance(c, 10 + i * 2);
+        }
+        offset_map
+    }
+
+    #[test]
+    fn test_every_char() {
+        let offset_map = offset_map(1);
+        assert_eq!(offset_map.source_range(0..1, 18), Some(10..12));
+        assert_eq!(offset_map.source_range(1..3, 18), Some(12..14));
+        assert_eq!(offset_map.source_range(3..6, 18), Some(14..16));
+        assert_eq!(offset_map.source_range(1..7, 18), Some(12..18));
+        assert_eq!(offset_map.source_range(7..7, 18), Some(18..18));
+        // Inside a multi-byte char
+        assert_eq!(offset_map.source_range(4..5, 18), Some(14..16));
+        assert_eq!(offset_map.source_range(0..8, 18), None);
+    }
+
+    #[test]
+    fn test_checkpoints() {
+        let offset_map = offset_map(3);
+        assert_eq!(offset_map.source_range(0..1, 18), Some(10..16));
+        assert_eq!(offset_map.source_range(3..6, 18), Some(10..16));
+        assert_eq!(offset_map.source_range(6..7, 18), Some(16..18));
+        assert_eq!(offset_map.source_range(2..7, 18), Some(10..18));
+    }
+
+    #[test]
+    fn test_empty() {
+        let offset_map = OffsetMap::new(4);
+        assert_eq!(offset_map.source_range(0..0, 3), Some(3..3));
+        assert_eq!(offset_map.source_range(0..1, 3), None);
+    }
+}
@@ -0,0 +1,165 @@
+use std::fmt;
+
+/// Where a character is in the source document.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    /// Bytes from the start of the document, counting any BOM
+    pub offset: usize,
+    /// Chars of decoded output before it
+    pub char_offset: usize,
+    /// Starting from 1
+    pub line: usize,
+    /// In chars, starting from 1
+    pub column: usize,
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "byte {} (char {}), line {}, column {}",
+            self.offset, self.char_offset, self.line, self.column
+        )
+    }
+}
+
+// Follows the line and column through decoded text, a char at a time. Line ends are the ones
+// that end-of-line handling recognizes, so "\r\n" is a single line end.
+#[derive(Clone)]
+pub struct LineTracker {
+    xml11: bool,
+    after_cr: bool,
+    chars: usize,
+    line: usize,
+    column: usize,
+}
+
+impl LineTracker {
+    pub fn new(xml11: bool) -> Self {
+        LineTracker {
+            xml11,
+            after_cr: false,
+            chars: 0,
+            line: 1,
+            column: 1,
+        }
+    }
+
+    // The position of the next char, which starts offset bytes into the document
+    pub fn position(&self, offset: usize) -> Position {
+        Position {
+            offset,
+            char_offset: self.chars,
+            line: self.line,
+            column: self.column,
+        }
+    }
+
+    pub fn advance(&mut self, c: char) {
+        self.chars += 1;
+        let after_cr = self.after_cr;
+        self.after_cr = c == '\r';
+        match c {
+            // The second half of a "\r\n" or (xml 1.1) "\r\u{85}" pair
+            '\n' if after_cr => {}
+            '\u{85}' if after_cr && self.xml11 => {}
+            '\r' | '\n' => self.new_line(),
+            '\u{85}' | '\u{2028}' if self.xml11 => self.new_line(),
+            _ => self.column += 1,
+        }
+    }
+
+    // Like advance for each char of text, which is valid utf-8. Outside of xml 1.1 every line
+    // end is ascii, so that only has to look at each byte.
+    pub fn advance_utf8(&mut self, text: &[u8]) {
+        if self.xml11 {
+            String::from_utf8_lossy(text)
+                .chars()
+                .for_each(|c| self.advance(c));
+            return;
+        }
+        for &b in text {
+            // Continuation bytes don't start a char
+            if b & 0xc0 == 0x80 {
+                continue;
+            }
+            self.chars += 1;
+            let after_cr = self.after_cr;
+            self.after_cr = b == b'\r';
+            match b {
+                b'\n' if after_cr => {}
+                b'\r' | b'\n' => self.new_line(),
+                _ => self.column += 1,
+            }
+        }
+    }
+
+    fn new_line(&mut self) {
+        self.line += 1;
+        self.column = 1;
+    }
+}
+
+#[cfg(test)]
+mod position_tests {
+    use super::*;
+
+    fn line_and_column(xml11: bool, text: &str) -> (usize, usize) {
+        let mut tracker = LineTracker::new(xml11);
+        text.chars().for_each(|c| tracker.advance(c));
+        let position = tracker.position(0);
+        (position.line, position.column)
+    }
+
+    #[test]
+    fn test_char_offset() {
+        let mut tracker = LineTracker::new(false);
+        "a\r\n\u{e9}".chars().for_each(|c| tracker.advance(c));
+        assert_eq!(
+            tracker.position(5),
+            Position {
+                offset: 5,
+                char_offset: 4,
+                line: 2,
+                column: 2
+            }
+        );
+    }
+
+    #[test]
+    fn test_line_ends() {
+        assert_eq!(line_and_column(false, ""), (1, 1));
+        assert_eq!(line_and_column(false, "ab"), (1, 3));
+        assert_eq!(line_and_column(false, "a\nb"), (2, 2));
+        assert_eq!(line_and_column(false, "a\r\nb"), (2, 2));
+        assert_eq!(line_and_column(false, "a\rb"), (2, 2));
+        assert_eq!(line_and_column(false, "a\r\rb"), (3, 2));
+        assert_eq!(line_and_column(false, "a\n\rb"), (3, 2));
+        assert_eq!(line_and_column(false, "a\u{85}\u{2028}b"), (1, 5));
+        assert_eq!(line_and_column(true, "a\u{85}\u{2028}b"), (3, 2));
+        assert_eq!(line_and_column(true, "a\r\u{85}b"), (2, 2));
+    }
+
+    #[test]
+    fn test_advance_utf8() {
+        for &xml11 in &[false, true] {
+            for text in &[
+                "",
+                "ab",
+                "a\r\n\u{e9}\r",
+                "\n\u{65e5}\u{85}\u{2028}\r\u{85}b",
+            ] {
+                let mut tracker = LineTracker::new(xml11);
+                text.chars().for_each(|c| tracker.advance(c));
+                let mut utf8_tracker = LineTracker::new(xml11);
+                let mid = (text.len() / 2..)
+                    .find(|&i| text.is_char_boundary(i))
+                    .unwrap_or(0);
+                let (start, end) = text.split_at(mid);
+                utf8_tracker.advance_utf8(start.as_bytes());
+                utf8_tracker.advance_utf8(end.as_bytes());
+                assert_eq!(utf8_tracker.position(0), tracker.position(0));
+            }
+        }
+    }
+}
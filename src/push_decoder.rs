@@ -0,0 +1,327 @@
+use enc_detect::DetectionReport;
+use error::{Error, Result};
+use position::Position;
+use reader::{DecodingReader, PendingInput, ReaderBuilder};
+use xml_decl::XmlDecl;
+
+use std::io;
+use std::ops::Range;
+
+pub fn new() -> PushDecoder {
+    ReaderBuilder::new().build_push_decoder()
+}
+
+pub fn with_capacity(capacity: usize) -> PushDecoder {
+    ReaderBuilder::new().capacity(capacity).build_push_decoder()
+}
+
+pub fn with_capacity_and_input_encoding(
+    capacity: usize,
+    suggested_encoding: Option<String>,
+) -> PushDecoder {
+    let mut builder = ReaderBuilder::new();
+    builder.capacity(capacity);
+    if let Some(suggested_encoding) = suggested_encoding {
+        builder.suggested_encoding(suggested_encoding);
+    }
+    builder.build_push_decoder()
+}
+
+impl ReaderBuilder {
+    /// Like `build`, but for input that arrives in chunks which are pushed to the decoder, rather
+    /// than read by it.
+    pub fn build_push_decoder(&self) -> PushDecoder {
+        PushDecoder {
+            builder: self.clone(),
+            prefix: Vec::new(),
+            decoding_reader: None,
+            output: String::new(),
+        }
+    }
+}
+
+/// Decodes a document that's fed to it in chunks of any size, and yields utf-8 as soon as it
+/// can. The input encoding is detected once enough of the document has been fed to decide it,
+/// so it never waits on input the way a `Read` does.
+///
+/// ```
+/// let mut decoder = xmlbufrw::push_decoder::new();
+/// let mut doc = String::new();
+/// doc.push_str(decoder.feed(b"<?xml version='1.0' encoding='iso-8859-1'?>")?);
+/// doc.push_str(decoder.feed(b"<a>caf")?);
+/// doc.push_str(decoder.feed(b"\xe9</a>")?);
+/// doc.push_str(decoder.finish()?);
+/// assert_eq!(doc, "<?xml version='1.0' encoding='iso-8859-1'?><a>caf\u{e9}</a>");
+/// # Ok::<(), xmlbufrw::Error>(())
+/// ```
+pub struct PushDecoder {
+    builder: ReaderBuilder,
+    // The start of the document, until there's enough of it for detection
+    prefix: Vec<u8>,
+    decoding_reader: Option<DecodingReader<PendingInput>>,
+    // What the last feed or finish decoded
+    output: String,
+}
+
+impl PushDecoder {
+    /// Decodes the next chunk of the document, and returns as much of the output as can be
+    /// decoded so far. That's nothing until the input encoding is detected, and otherwise
+    /// leaves off any sequence that's cut off by the end of the chunk, until the next one.
+    pub fn feed(&mut self, chunk: &[u8]) -> Result<&str> {
+        self.output.clear();
+        match self.decoding_reader {
+            Some(ref mut decoding_reader) => decoding_reader.get_mut().push(chunk),
+            None => {
+                self.prefix.extend_from_slice(chunk);
+                self.detect(false)?;
+            }
+        }
+        self.decode()?;
+        Ok(&self.output)
+    }
+
+    /// Ends the document, and returns the rest of the output. Fails if the document ends with
+    /// an incomplete sequence, or if it ends before detection could decide the input encoding.
+    /// Chunks that are fed after this are ignored.
+    pub fn finish(&mut self) -> Result<&str> {
+        self.output.clear();
+        match self.decoding_reader {
+            Some(ref mut decoding_reader) => decoding_reader.get_mut().set_eof(),
+            None => self.detect(true)?,
+        }
+        self.decode()?;
+        Ok(&self.output)
+    }
+
+    /// How the input encoding was decided, once it has been.
+    pub fn detection_report(&self) -> Option<&DetectionReport> {
+        self.decoding_reader
+            .as_ref()
+            .map(|decoding_reader| decoding_reader.detection_report())
+    }
+
+    /// The document's xml declaration, if it has one and the input encoding has been detected.
+    pub fn xml_decl(&self) -> Option<&XmlDecl> {
+        self.decoding_reader
+            .as_ref()
+            .and_then(|decoding_reader| decoding_reader.xml_decl())
+    }
+
+    /// Where each malformed sequence that was replaced or skipped started in the document.
+    pub fn substitutions(&self) -> &[usize] {
+        self.decoding_reader
+            .as_ref()
+            .map_or(&[], |decoding_reader| decoding_reader.substitutions())
+    }
+
+    /// Where the next char to be decoded comes from, once the input encoding has been detected.
+    pub fn position(&self) -> Option<Position> {
+        self.decoding_reader
+            .as_ref()
+            .map(|decoding_reader| decoding_reader.position())
+    }
+
+    /// See `DecodingReader::source_range`.
+    pub fn source_range(&self, decoded: Range<usize>) -> Option<Range<usize>> {
+        self.decoding_reader
+            .as_ref()
+            .and_then(|decoding_reader| decoding_reader.source_range(decoded))
+    }
+
+    // Runs detection over everything that's been fed so far, unless it's already done
+    fn detect(&mut self, eof: bool) -> Result<()> {
+        if let Some(result) = self.builder.build_from_prefix(&self.prefix, eof) {
+            self.decoding_reader = Some(result?);
+            self.prefix = Vec::new();
+        }
+        Ok(())
+    }
+
+    // Decodes everything that's been fed to the decoding reader into output
+    fn decode(&mut self) -> Result<()> {
+        let decoding_reader = match self.decoding_reader {
+            Some(ref mut decoding_reader) => decoding_reader,
+            None => return Ok(()),
+        };
+        loop {
+            match decoding_reader.fill_output_buf() {
+                Ok(()) => {}
+                Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+            let output_len = self.output.len();
+            decoding_reader.take_output(&mut self.output);
+            // The document is done
+            if self.output.len() == output_len {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod push_decoder_tests {
+    use super::*;
+    use reader::MalformedInputHandling;
+
+    // Each test doc, and the validation doc with the same text in utf-8, no bom
+    const DOCS: &[(&[u8], &[u8])] = &[
+        (
+            include_bytes!("../tests/utf8/doc.xml") as &[u8],
+            include_bytes!("../tests/validation/utf8.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/utf8_bom/doc_xmldecl.xml") as &[u8],
+            include_bytes!("../tests/validation/utf8_xmldecl.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/utf16le_bom/doc.xml") as &[u8],
+            include_bytes!("../tests/validation/utf16le.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/utf16be/doc_xmldecl_encodingdecl.xml") as &[u8],
+            include_bytes!("../tests/validation/utf16be_xmldecl_encodingdecl.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/utf32le/doc_xmldecl.xml") as &[u8],
+            include_bytes!("../tests/validation/utf32le_xmldecl.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/utf32be_bom/doc_xmldecl_encodingdecl.xml") as &[u8],
+            include_bytes!("../tests/validation/utf32be_xmldecl_encodingdecl.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/ibm037/doc_xmldecl_encodingdecl.xml") as &[u8],
+            include_bytes!("../tests/validation/ibm037_xmldecl_encodingdecl.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/shift_jis/doc_xmldecl_encodingdecl.xml") as &[u8],
+            include_bytes!("../tests/validation/shift_jis_xmldecl_encodingdecl.xml") as &[u8],
+        ),
+        (
+            include_bytes!("../tests/iso-2022-jp/doc_xmldecl_encodingdecl.xml") as &[u8],
+            include_bytes!("../tests/validation/iso-2022-jp_xmldecl_encodingdecl.xml") as &[u8],
+        ),
+    ];
+
+    fn decode_in_chunks(decoder: &mut PushDecoder, doc: &[u8], chunk_len: usize) -> Result<String> {
+        let mut utf8_encoded_doc = String::new();
+        for chunk in doc.chunks(chunk_len) {
+            utf8_encoded_doc.push_str(decoder.feed(chunk)?);
+        }
+        utf8_encoded_doc.push_str(decoder.finish()?);
+        Ok(utf8_encoded_doc)
+    }
+
+    #[test]
+    fn test_docs() {
+        for &(doc, utf8_validation) in DOCS {
+            for &chunk_len in &[1, 3, 64, doc.len()] {
+                for &capacity in &[1, 16] {
+                    let utf8_encoded_doc =
+                        decode_in_chunks(&mut with_capacity(capacity), doc, chunk_len)
+                            .expect("Failed decoding input data");
+                    assert_eq!(utf8_validation, utf8_encoded_doc.as_bytes());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_detection() {
+        let mut decoder = new();
+        assert_eq!(
+            decoder
+                .feed(b"<\0?\0x\0m\0l\0")
+                .expect("Failed decoding input data"),
+            ""
+        );
+        assert!(decoder.detection_report().is_none());
+        assert_eq!(
+            decoder
+                .feed(b" \0v\0e\0r\0s\0i\0o\0n\0=\0'\x001\0.\x000\0'\0?\0>\0")
+                .expect("Failed decoding input data"),
+            "<?xml version='1.0'?>"
+        );
+        match decoder.detection_report() {
+            Some(detection) => assert_eq!(detection.encoding, "utf-16le".to_string()),
+            None => panic!("Detection should be done once the xml declaration is complete"),
+        }
+        assert_eq!(decoder.position().map(|position| position.offset), Some(42));
+        assert_eq!(
+            decoder.feed(b"<\0a\0").expect("Failed decoding input data"),
+            "<a"
+        );
+        // A char that's cut off waits for the rest of it
+        assert_eq!(decoder.feed(b"/").expect("Failed decoding input data"), "");
+        assert_eq!(
+            decoder.feed(b"\0>").expect("Failed decoding input data"),
+            "/"
+        );
+        assert_eq!(
+            decoder.feed(b"\0").expect("Failed decoding input data"),
+            ">"
+        );
+        assert_eq!(decoder.finish().expect("Failed decoding input data"), "");
+        assert_eq!(
+            decoder.feed(b"<\0").expect("Failed decoding input data"),
+            ""
+        );
+
+        // Enough bytes to tell there's no xml declaration are enough
+        let mut decoder = new();
+        assert_eq!(
+            decoder.feed(b"<a/>").expect("Failed decoding input data"),
+            ""
+        );
+        assert_eq!(
+            decoder.feed(b"<b/>").expect("Failed decoding input data"),
+            "<a/><b/>"
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        match new().finish() {
+            Ok(output) => assert_eq!(output, ""),
+            Err(e) => panic!("Unexpected error: {}", e),
+        }
+
+        let mut decoder = new();
+        match decoder.feed(b"<\0a\0/\0>\0") {
+            Err(Error::NoBomNoDecl) => {}
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("Utf-16 without a BOM or xml declaration should fail detection"),
+        }
+
+        let mut decoder = new();
+        match decoder.feed(b"<?xml version='1.0'") {
+            Ok(output) => assert_eq!(output, ""),
+            Err(e) => panic!("Unexpected error: {}", e),
+        }
+        match decoder.finish() {
+            Err(Error::MalformedXmlDecl(_)) => {}
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("An unterminated xml declaration should fail detection"),
+        }
+
+        let mut decoder = new();
+        match decoder.feed(b"<a>bc\xe2\x82") {
+            Ok(output) => assert_eq!(output, "<a>bc"),
+            Err(e) => panic!("Unexpected error: {}", e),
+        }
+        match decoder.finish() {
+            Err(Error::MalformedInput { position }) => assert_eq!(position.offset, 5),
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("A truncated sequence should fail decoding"),
+        }
+
+        let mut decoder = ReaderBuilder::new()
+            .malformed_input_handling(MalformedInputHandling::Replace)
+            .build_push_decoder();
+        let utf8_encoded_doc =
+            decode_in_chunks(&mut decoder, b"<a>\xff</a>", 2).expect("Failed decoding input data");
+        assert_eq!(utf8_encoded_doc, "<a>\u{fffd}</a>");
+        assert_eq!(decoder.substitutions(), &[3]);
+    }
+}
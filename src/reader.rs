@@ -1,42 +1,1093 @@
+use chars::is_legal_char;
+use content_type::{charset_parameter, content_type_charset};
 use enc_detect::detect_encoding_with_suggestion;
+use enc_detect::utf8_complete_len;
+use enc_detect::Decoder;
+use enc_detect::DetectionReport;
+use enc_detect::Encoding;
+use enc_detect::SequenceScanner;
+use enc_detect::SourceLen;
+use enc_detect::SuggestedEncodingPolicy;
+use eol::EolNormalizer;
+use error::{Error, Result};
+use offset_map::OffsetMap;
+use position::{LineTracker, Position};
+use xml_decl::XmlDecl;
 
-use encodingbufrw::reader::CodecReadBuffer;
-use encodingbufrw::DEFAULT_BUF_SIZE;
+use DEFAULT_BUF_SIZE;
+
+use encoding_rs;
 
 use std::io;
+use std::io::{BufRead, Read};
+use std::ops::Range;
+
+pub fn new<R: Read>(inner: R) -> Result<DecodingReader<R>> {
+    ReaderBuilder::new().build(inner)
+}
 
-pub fn new<R: std::io::Read>(inner: R) -> io::Result<CodecReadBuffer<R>> {
-    with_capacity_and_input_encoding(inner, DEFAULT_BUF_SIZE, None)
+pub fn with_capacity<R: Read>(inner: R, capacity: usize) -> Result<DecodingReader<R>> {
+    ReaderBuilder::new().capacity(capacity).build(inner)
 }
 
-pub fn with_capacity<R: std::io::Read>(
+pub fn with_capacity_and_input_encoding<R: Read>(
     inner: R,
     capacity: usize,
-) -> io::Result<CodecReadBuffer<R>> {
-    with_capacity_and_input_encoding(inner, capacity, None)
+    suggested_encoding: Option<String>,
+) -> Result<DecodingReader<R>> {
+    let mut builder = ReaderBuilder::new();
+    builder.capacity(capacity);
+    if let Some(suggested_encoding) = suggested_encoding {
+        builder.suggested_encoding(suggested_encoding);
+    }
+    builder.build(inner)
 }
 
-pub fn with_capacity_and_input_encoding<R: std::io::Read>(
-    mut inner: R,
+/// Collects the options for a `DecodingReader`, which `build` then creates.
+///
+/// ```no_run
+/// use xmlbufrw::reader::{MalformedInputHandling, ReaderBuilder};
+///
+/// let doc = std::fs::File::open("doc.xml")?;
+/// let decoding_reader = ReaderBuilder::new()
+///     .suggested_encoding("windows-1252")
+///     .malformed_input_handling(MalformedInputHandling::Replace)
+///     .eol_normalization(true)
+///     .build(doc)?;
+/// # Ok::<(), xmlbufrw::Error>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct ReaderBuilder {
     capacity: usize,
     suggested_encoding: Option<String>,
-) -> io::Result<CodecReadBuffer<R>> {
-    let (encoding, prebuf) = detect_encoding_with_suggestion(suggested_encoding, &mut inner)?;
-    let encoding_name = encoding.get_name();
+    suggested_encoding_policy: SuggestedEncodingPolicy,
+    malformed_input: MalformedInputHandling,
+    xml_decl_handling: XmlDeclHandling,
+    eol_normalization: bool,
+    char_validation: bool,
+    offset_map: Option<usize>,
+    size_limit: Option<usize>,
+    utf8_passthrough: bool,
+}
+
+impl Default for ReaderBuilder {
+    fn default() -> Self {
+        ReaderBuilder::new()
+    }
+}
+
+impl ReaderBuilder {
+    pub fn new() -> Self {
+        ReaderBuilder {
+            capacity: DEFAULT_BUF_SIZE,
+            suggested_encoding: None,
+            suggested_encoding_policy: SuggestedEncodingPolicy::Fallback,
+            malformed_input: MalformedInputHandling::Strict,
+            xml_decl_handling: XmlDeclHandling::Keep,
+            eol_normalization: false,
+            char_validation: false,
+            offset_map: None,
+            size_limit: None,
+            utf8_passthrough: true,
+        }
+    }
+
+    /// How many bytes of input to read at a time. A capacity of 0 is taken as 1.
+    pub fn capacity(&mut self, capacity: usize) -> &mut Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// The input encoding according to something other than the document, e.g. a transport
+    /// protocol. By default it's only used when the document has neither a byte order mark nor
+    /// an encoding declaration.
+    pub fn suggested_encoding<S: Into<String>>(&mut self, encoding: S) -> &mut Self {
+        self.suggested_encoding = Some(encoding.into());
+        self
+    }
+
+    /// How the suggested encoding ranks against what the document says about its own encoding.
+    /// The default is `SuggestedEncodingPolicy::Fallback`.
+    pub fn suggested_encoding_policy(&mut self, policy: SuggestedEncodingPolicy) -> &mut Self {
+        self.suggested_encoding_policy = policy;
+        self
+    }
+
+    /// Suggests the encoding that an HTTP or MIME `Content-Type` header value gives, if any. An
+    /// explicit `charset` parameter overrides the document as
+    /// [RFC 3023](https://tools.ietf.org/html/rfc3023) requires, while the "us-ascii" that
+    /// `text/xml` defaults to is only a fallback. See `content_type_charset`.
+    pub fn content_type(&mut self, content_type: &str) -> &mut Self {
+        if let Some(charset) = charset_parameter(content_type) {
+            self.suggested_encoding = Some(charset);
+            self.suggested_encoding_policy = SuggestedEncodingPolicy::Override;
+        } else if let Some(charset) = content_type_charset(content_type) {
+            // Plenty of servers send text/xml for utf-8 documents, which a us-ascii override
+            // would garble
+            self.suggested_encoding = Some(charset);
+            self.suggested_encoding_policy = SuggestedEncodingPolicy::Fallback;
+        }
+        self
+    }
+
+    /// See `DecodingReader::set_malformed_input_handling`.
+    pub fn malformed_input_handling(&mut self, handling: MalformedInputHandling) -> &mut Self {
+        self.malformed_input = handling;
+        self
+    }
+
+    /// See `DecodingReader::set_xml_decl_handling`.
+    pub fn xml_decl_handling(&mut self, handling: XmlDeclHandling) -> &mut Self {
+        self.xml_decl_handling = handling;
+        self
+    }
+
+    /// See `DecodingReader::set_eol_normalization`.
+    pub fn eol_normalization(&mut self, enabled: bool) -> &mut Self {
+        self.eol_normalization = enabled;
+        self
+    }
+
+    /// See `DecodingReader::set_char_validation`.
+    pub fn char_validation(&mut self, enabled: bool) -> &mut Self {
+        self.char_validation = enabled;
+        self
+    }
+
+    /// See `DecodingReader::set_offset_map`.
+    pub fn offset_map(&mut self, interval: Option<usize>) -> &mut Self {
+        self.offset_map = interval;
+        self
+    }
+
+    /// See `DecodingReader::set_size_limit`.
+    pub fn size_limit(&mut self, limit: Option<usize>) -> &mut Self {
+        self.size_limit = limit;
+        self
+    }
+
+    /// See `DecodingReader::set_utf8_passthrough`.
+    pub fn utf8_passthrough(&mut self, enabled: bool) -> &mut Self {
+        self.utf8_passthrough = enabled;
+        self
+    }
+
+    /// Detects the input encoding of `inner`, and wraps it in a reader that decodes it to
+    /// utf-8.
+    pub fn build<R: Read>(&self, mut inner: R) -> Result<DecodingReader<R>> {
+        let (encoding, prebuf, detection) = self.detect(&mut inner)?;
+        self.build_detected(inner, encoding, prebuf, detection)
+    }
+
+    pub(crate) fn detect<R: Read>(
+        &self,
+        inner: &mut R,
+    ) -> Result<(Encoding, Vec<u8>, DetectionReport)> {
+        detect_encoding_with_suggestion(
+            self.suggested_encoding.clone(),
+            self.suggested_encoding_policy,
+            inner,
+        )
+    }
+
+    // For input that's pushed rather than read: detects the input encoding from prefix, the
+    // start of the document, and wraps the rest of it. None if detection needs more of the
+    // document than prefix, unless eof says that's all there is.
+    pub(crate) fn build_from_prefix(
+        &self,
+        prefix: &[u8],
+        eof: bool,
+    ) -> Option<Result<DecodingReader<PendingInput>>> {
+        let mut input = DetectionInput {
+            data: prefix,
+            exhausted: false,
+        };
+        let detected = self.detect(&mut input);
+        if input.exhausted && !eof {
+            return None;
+        }
+        let pending = PendingInput {
+            buf: input.data.to_vec(),
+            pos: 0,
+            eof,
+        };
+        Some(detected.and_then(|(encoding, prebuf, detection)| {
+            self.build_detected(pending, encoding, prebuf, detection)
+        }))
+    }
+
+    // Wraps inner, whose first bytes detection has already taken as the prebuf
+    pub(crate) fn build_detected<R: Read>(
+        &self,
+        inner: R,
+        encoding: Encoding,
+        prebuf: Vec<u8>,
+        detection: DetectionReport,
+    ) -> Result<DecodingReader<R>> {
+        let decoder = encoding.get_decoder()?;
+        // The prebuf starts after the BOM
+        let input_offset = match detection.bom {
+            Some(_) => encoding.get_bom().map_or(0, |bom| bom.len()),
+            None => 0,
+        };
+
+        // Initialize the input_buf from the pre-buffered data
+        // if prebuf is bigger than the requested capacity, we'll increase the capacity to the
+        // size of the pre-buffered data
+        // When there's an xml declaration, the prebuf ends with its closing '?>'
+        let xml_decl_len = if detection.xml_decl.is_some() {
+            prebuf.len()
+        } else {
+            0
+        };
+        // A read into no room at all would look like the end of the input
+        let capacity = std::cmp::max(self.capacity, 1);
+        let mut input_buf: Vec<u8> = Vec::with_capacity(std::cmp::max(capacity, prebuf.len()));
+        input_buf.extend(prebuf);
+        let xml11 = detection
+            .xml_decl
+            .as_ref()
+            .map_or(false, |xml_decl| xml_decl.version == "1.1");
+
+        let scanner = decoder.sequence_scanner();
+        let mut decoding_reader = DecodingReader {
+            inner,
+            decoder,
+            scanner,
+            detection,
+            input_buf,
+            input_offset,
+            decoder_pending: 0,
+            xml_decl_len,
+            eol_normalizer: None,
+            validate_chars: false,
+            malformed_input: MalformedInputHandling::Strict,
+            substitutions: Vec::new(),
+            checkpoints: vec![(0, input_offset)],
+            char_offset: input_offset,
+            line_tracker: LineTracker::new(xml11),
+            tracked_pos: 0,
+            read_offset: input_offset,
+            next_checkpoint: 0,
+            offset_map: None,
+            size_limit: None,
+            utf8_passthrough: self.utf8_passthrough,
+            borrowed: false,
+            capacity,
+            output_buf: String::with_capacity(capacity),
+            output_pos: 0,
+            eof: false,
+            finished: false,
+        };
+        decoding_reader.set_size_limit(self.size_limit);
+        decoding_reader.check_size_limit()?;
+        // The offset map has to be started before a rewritten xml declaration is output
+        decoding_reader.set_offset_map(self.offset_map);
+        decoding_reader.set_xml_decl_handling(self.xml_decl_handling);
+        decoding_reader.set_eol_normalization(self.eol_normalization);
+        decoding_reader.set_char_validation(self.char_validation);
+        decoding_reader.set_malformed_input_handling(self.malformed_input);
+        Ok(decoding_reader)
+    }
+}
+
+// The start of a document for detection, which notes whether detection wanted more than that
+struct DetectionInput<'a> {
+    data: &'a [u8],
+    exhausted: bool,
+}
+
+impl<'a> Read for DetectionInput<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.len() > self.data.len() {
+            self.exhausted = true;
+        }
+        self.data.read(buf)
+    }
+}
+
+// Input that's pushed to a DecodingReader instead of read by it. Reading more than has been
+// pushed fails with WouldBlock, which the DecodingReader recovers from without losing anything.
+pub(crate) struct PendingInput {
+    buf: Vec<u8>,
+    pos: usize,
+    // Nothing more is going to be pushed
+    eof: bool,
+}
+
+impl PendingInput {
+    pub(crate) fn push(&mut self, data: &[u8]) {
+        self.buf.drain(..self.pos);
+        self.pos = 0;
+        self.buf.extend_from_slice(data);
+    }
+
+    pub(crate) fn set_eof(&mut self) {
+        self.eof = true;
+    }
+}
+
+impl Read for PendingInput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = &self.buf[self.pos..];
+        if available.is_empty() && !self.eof {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let bytes_read = std::cmp::min(buf.len(), available.len());
+        buf[..bytes_read].copy_from_slice(&available[..bytes_read]);
+        self.pos += bytes_read;
+        Ok(bytes_read)
+    }
+}
+
+// A stretch of decoded text, with where each of its chars starts in the document: where the
+// char before it ends, going by source_len, unless a checkpoint says otherwise
+struct CharOffsets<'a> {
+    chars: std::str::CharIndices<'a>,
+    // Where the text starts in output_buf, which the checkpoints' indices are into
+    start: usize,
+    checkpoints: &'a [(usize, usize)],
+    source_len: SourceLen,
+    offset: usize,
+}
+
+impl<'a> CharOffsets<'a> {
+    fn new(
+        text: &'a str,
+        start: usize,
+        checkpoints: &'a [(usize, usize)],
+        source_len: SourceLen,
+        offset: usize,
+    ) -> Self {
+        CharOffsets {
+            chars: text.char_indices(),
+            start,
+            checkpoints,
+            source_len,
+            offset,
+        }
+    }
+}
+
+impl<'a> Iterator for CharOffsets<'a> {
+    type Item = (char, usize);
+
+    fn next(&mut self) -> Option<(char, usize)> {
+        let (i, c) = self.chars.next()?;
+        let index = self.start + i;
+        // Later checkpoints for the same char win
+        while let Some((&(checkpoint_index, offset), rest)) = self.checkpoints.split_first() {
+            if checkpoint_index > index {
+                break;
+            }
+            if checkpoint_index == index {
+                self.offset = offset;
+            }
+            self.checkpoints = rest;
+        }
+        let offset = self.offset;
+        self.offset += self.source_len.of(c);
+        Some((c, offset))
+    }
+}
+
+/// What to do with the xml declaration at the start of a document, which names the input
+/// encoding even though the decoded output is utf-8.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XmlDeclHandling {
+    /// Pass the declaration through unchanged
+    Keep,
+    /// Leave the declaration out of the decoded output
+    Strip,
+    /// Replace the declaration with one that says `encoding="utf-8"`
+    RewriteEncoding,
+}
+
+/// What to do with input that isn't valid in the input encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MalformedInputHandling {
+    /// Fail with `Error::MalformedInput`
+    Strict,
+    /// Decode each malformed sequence as U+FFFD REPLACEMENT CHARACTER
+    Replace,
+    /// Leave malformed sequences out of the decoded output
+    Skip,
+}
+
+/// Reads from the wrapped reader in the detected input encoding, and yields utf-8.
+// It takes the place of encodingbufrw's CodecReadBuffer, which only drives encoding_rs decoders,
+// and so can't decode every encoding we can detect (e.g. UTF-32)
+pub struct DecodingReader<R: Read> {
+    inner: R,
+    decoder: Decoder,
+    scanner: Option<SequenceScanner>,
+    detection: DetectionReport,
+    // Input that hasn't been decoded yet
+    input_buf: Vec<u8>,
+    // Where input_buf starts in the document
+    input_offset: usize,
+    // How many bytes at the start of input_buf the decoder has already taken, as the start of a
+    // char that was cut off. They're kept for the scanner, which hasn't seen the whole char yet.
+    decoder_pending: usize,
+    // The length of the xml declaration at the start of input_buf, until it's been decoded
+    xml_decl_len: usize,
+    eol_normalizer: Option<EolNormalizer>,
+    validate_chars: bool,
+    malformed_input: MalformedInputHandling,
+    // Where each malformed sequence that was replaced or skipped started in the document
+    substitutions: Vec<usize>,
+    // Where chars of output_buf start in the document, as (index into output_buf, offset)
+    // pairs. Each char starts where the one before it ends, going by its SourceLen, unless
+    // there's a checkpoint for it. There's always one for the start of output_buf.
+    checkpoints: Vec<(usize, usize)>,
+    // Where the next decoded char starts in the document, going by the checkpoints
+    char_offset: usize,
+    // Follows the output as it's read, up to tracked_pos in output_buf, where the next char
+    // starts read_offset bytes into the document, unless checkpoints[next_checkpoint] is for it
+    line_tracker: LineTracker,
+    tracked_pos: usize,
+    read_offset: usize,
+    next_checkpoint: usize,
+    offset_map: Option<OffsetMap>,
+    size_limit: Option<usize>,
+    utf8_passthrough: bool,
+    // fill_str returned text from the wrapped reader's buffer, not from output_buf
+    borrowed: bool,
+    capacity: usize,
+    // Decoded data that hasn't been read yet starts at output_pos
+    output_buf: String,
+    output_pos: usize,
+    // The wrapped reader is exhausted
+    eof: bool,
+    // The decoder has been flushed
+    finished: bool,
+}
+
+impl<R: Read> DecodingReader<R> {
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Describes how the input encoding was decided.
+    pub fn detection_report(&self) -> &DetectionReport {
+        &self.detection
+    }
+
+    /// The document's xml declaration, if it has one.
+    pub fn xml_decl(&self) -> Option<&XmlDecl> {
+        self.detection.xml_decl.as_ref()
+    }
+
+    /// The xml version from the document's xml declaration, e.g. "1.0" or "1.1".
+    pub fn version(&self) -> Option<&str> {
+        self.xml_decl().map(|decl| decl.version.as_str())
+    }
+
+    /// Whether the document's xml declaration says `standalone="yes"` or `standalone="no"`.
+    pub fn standalone(&self) -> Option<bool> {
+        self.xml_decl().and_then(|decl| decl.standalone)
+    }
+
+    /// Chooses what happens to the document's xml declaration in the decoded output. It only
+    /// has an effect before the first read.
+    pub fn set_xml_decl_handling(&mut self, handling: XmlDeclHandling) {
+        if self.xml_decl_len == 0 {
+            return;
+        }
+        match handling {
+            XmlDeclHandling::Keep => return,
+            XmlDeclHandling::Strip => {}
+            XmlDeclHandling::RewriteEncoding => {
+                if let Some(xml_decl) = self.detection.xml_decl.as_ref() {
+                    let utf8_decl = XmlDecl {
+                        encoding: Some("utf-8".to_string()),
+                        ..xml_decl.clone()
+                    };
+                    // The rewritten declaration stands in for the original one
+                    for c in utf8_decl.to_string().chars() {
+                        let offset = self.input_offset;
+                        self.set_char_offset(offset);
+                        self.output_buf.push(c);
+                        self.char_offset += self.decoder.source_len().of(c);
+                        self.map_output_char(c, offset);
+                    }
+                }
+            }
+        }
+        self.input_buf.drain(..self.xml_decl_len);
+        self.input_offset += self.xml_decl_len;
+        let offset = self.input_offset;
+        self.set_char_offset(offset);
+        self.xml_decl_len = 0;
+    }
+
+    /// Turns end-of-line normalization on or off. When it's on, line ends are translated to
+    /// "\n" as section 2.11 of the xml specification requires, following the xml 1.1 rules if
+    /// the document declares version 1.1, and the xml 1.0 rules otherwise. It only affects text
+    /// that hasn't been decoded yet, so it should be set before the first read.
+    pub fn set_eol_normalization(&mut self, enabled: bool) {
+        self.eol_normalizer = if enabled {
+            Some(EolNormalizer::new(self.version() == Some("1.1")))
+        } else {
+            None
+        };
+    }
+
+    /// Turns checking decoded text for chars that xml doesn't allow on or off. When it's on,
+    /// reading fails at the first char outside production \[2\] of the xml 1.0 specification, or,
+    /// if the document declares version 1.1, at the first char that's outside production \[2\] or
+    /// a RestrictedChar in xml 1.1. It only affects text that hasn't been decoded yet, so it
+    /// should be set before the first read.
+    pub fn set_char_validation(&mut self, enabled: bool) {
+        self.validate_chars = enabled;
+    }
+
+    /// Chooses what happens to input that isn't valid in the input encoding. The default is
+    /// `MalformedInputHandling::Strict`.
+    pub fn set_malformed_input_handling(&mut self, handling: MalformedInputHandling) {
+        self.malformed_input = handling;
+    }
+
+    /// Where each malformed sequence that has been replaced or skipped so far started, in
+    /// bytes from the start of the document.
+    pub fn substitutions(&self) -> &[usize] {
+        &self.substitutions
+    }
+
+    /// Where the next char to be read comes from. Line and column are counted in the decoded
+    /// output, so a stripped or rewritten xml declaration isn't counted as it was in the source.
+    pub fn position(&self) -> Position {
+        let offset = self.checkpoints[self.next_checkpoint..]
+            .iter()
+            .take_while(|&&(index, _)| index == self.tracked_pos)
+            .last()
+            .map_or(self.read_offset, |&(_, offset)| offset);
+        self.line_tracker.position(offset)
+    }
+
+    /// Starts keeping a map from offsets in the decoded output back to the source bytes they
+    /// were decoded from, with a checkpoint every `interval` chars, or with `None`, stops. The
+    /// map only covers output that's decoded after it's started, so it should be set before the
+    /// first read.
+    pub fn set_offset_map(&mut self, interval: Option<usize>) {
+        self.offset_map = interval.map(OffsetMap::new);
+    }
+
+    /// The bytes of the source document that the `decoded` range of the output came from. With
+    /// a checkpoint interval of 1 that's exactly the bytes of the chars in the range, otherwise
+    /// it can extend to the checkpoints on either side of it. `None` if there's no offset map,
+    /// or the range hasn't been decoded yet.
+    pub fn source_range(&self, decoded: Range<usize>) -> Option<Range<usize>> {
+        self.offset_map
+            .as_ref()
+            .and_then(|offset_map| offset_map.source_range(decoded, self.char_offset))
+    }
+
+    /// Limits the length of the document, counting any byte order mark, to `limit` bytes.
+    /// Reading fails with `Error::SizeLimitExceeded` once more than that has been read.
+    pub fn set_size_limit(&mut self, limit: Option<usize>) {
+        self.size_limit = limit;
+    }
+
+    /// Turns passing utf-8 input through as it is on or off. When it's on, which is the default,
+    /// input in utf-8 is only validated rather than decoded, as long as there's nothing else to
+    /// do to it: no end-of-line normalization, char validation or offset map. Turning it off
+    /// makes no difference to the output, and is mostly useful for comparing the two.
+    pub fn set_utf8_passthrough(&mut self, enabled: bool) {
+        self.utf8_passthrough = enabled;
+    }
+
+    fn passes_through(&self) -> bool {
+        self.utf8_passthrough
+            && self.decoder.is_utf8()
+            && self.eol_normalizer.is_none()
+            && !self.validate_chars
+            && self.offset_map.is_none()
+    }
+
+    // Where the rest of the document starts, if none of it has been decoded yet and decoding it
+    // would give it back unchanged, as long as it's valid utf-8
+    pub(crate) fn passthrough_offset(&self) -> Option<usize> {
+        if self.passes_through() && self.output_pos >= self.output_buf.len() && !self.finished {
+            Some(self.input_offset)
+        } else {
+            None
+        }
+    }
+
+    fn check_size_limit(&self) -> Result<()> {
+        self.check_size_limit_at(self.input_offset + self.input_buf.len())
+    }
+
+    // Whether a document that's at least len bytes long is within the limit
+    pub(crate) fn check_size_limit_at(&self, len: usize) -> Result<()> {
+        match self.size_limit {
+            Some(limit) if len > limit => Err(Error::SizeLimitExceeded { limit }),
+            _ => Ok(()),
+        }
+    }
+
+    fn fill_input_buf(&mut self) -> Result<()> {
+        let start = self.input_buf.len();
+        self.input_buf.resize(start + self.capacity, 0);
+        let result = self.inner.read(&mut self.input_buf[start..]);
+        self.input_buf
+            .truncate(start + *result.as_ref().unwrap_or(&0));
+        if result? == 0 {
+            self.eof = true;
+        }
+        self.check_size_limit()
+    }
+
+    fn decode_input_buf(&mut self) -> Result<()> {
+        self.xml_decl_len = 0;
+        self.clear_output();
+
+        // A utf-8 sequence that's cut off at the end of the input waits in input_buf for the
+        // rest of it, rather than in the decoder, where passing input through would miss it
+        let cut_off = if self.decoder.is_utf8() && !self.eof {
+            let complete_len = utf8_complete_len(&self.input_buf);
+            self.input_buf.split_off(complete_len)
+        } else {
+            Vec::new()
+        };
+        let result = if self.passes_through() && self.pass_through_input_buf() {
+            Ok(())
+        } else {
+            self.decode_input_buf_with_decoder()
+        };
+        self.input_buf.extend_from_slice(&cut_off);
+        result
+    }
+
+    // Makes valid utf-8 in input_buf the output, as it is, without copying it. Returns false for
+    // malformed input, which is left for the decoder to deal with.
+    fn pass_through_input_buf(&mut self) -> bool {
+        let mut spare = std::mem::take(&mut self.output_buf).into_bytes();
+        spare.clear();
+        let input_buf = std::mem::replace(&mut self.input_buf, spare);
+        match String::from_utf8(input_buf) {
+            Ok(decoded) => self.output_buf = decoded,
+            Err(e) => {
+                let spare = std::mem::replace(&mut self.input_buf, e.into_bytes());
+                self.output_buf = String::from_utf8(spare).unwrap_or_default();
+                return false;
+            }
+        }
+        self.input_offset += self.output_buf.len();
+        self.char_offset = self.input_offset;
+        if self.eof {
+            self.finished = true;
+        }
+        true
+    }
+
+    fn decode_input_buf_with_decoder(&mut self) -> Result<()> {
+        // Malformed input ends a call to the decoder, so with lenient handling it takes a call
+        // for each malformed sequence
+        let mut bytes_read = self.decoder_pending;
+        // Where the next char starts in input_buf, as far as the scanner has got
+        let mut char_start = 0;
+        loop {
+            let needed = self
+                .decoder
+                .max_utf8_buffer_length_without_replacement(self.input_buf.len() - bytes_read)
+                .unwrap_or((self.input_buf.len() - bytes_read) * 4);
+            self.output_buf.reserve(needed);
+            let decoded_len = self.output_buf.len();
+            let checkpoints_len = self.checkpoints.len();
+            let start_offset = self.char_offset;
+            let (result, read) = self.decoder.decode_to_string_without_replacement(
+                &self.input_buf[bytes_read..],
+                &mut self.output_buf,
+                self.eof,
+            );
+            bytes_read += read;
+            char_start = self.track_char_offsets(decoded_len, char_start, bytes_read);
+            self.process_decoded(decoded_len, checkpoints_len, start_offset)?;
+            match result {
+                encoding_rs::DecoderResult::Malformed(malformed_len, consumed_after) => {
+                    let resume = bytes_read - consumed_after as usize;
+                    let offset = self.input_offset + resume - malformed_len as usize;
+                    match self.malformed_input {
+                        MalformedInputHandling::Strict => {
+                            return Err(Error::MalformedInput {
+                                position: self.output_position(offset),
+                            })
+                        }
+                        MalformedInputHandling::Replace => self.push_char('\u{FFFD}', offset)?,
+                        MalformedInputHandling::Skip => {}
+                    }
+                    self.substitutions.push(offset);
+                    let resume_offset = self.input_offset + resume;
+                    self.set_char_offset(resume_offset);
+                    char_start = resume;
+                }
+                encoding_rs::DecoderResult::InputEmpty if self.eof => {
+                    self.finished = true;
+                    break;
+                }
+                _ => break,
+            }
+        }
+
+        let consumed = if self.scanner.is_some() {
+            char_start
+        } else {
+            bytes_read
+        };
+        self.decoder_pending = bytes_read - consumed;
+        self.input_buf.drain(..consumed);
+        self.input_offset += consumed;
+        Ok(())
+    }
+
+    // Moves char_offset past the chars that were just decoded onto the end of output_buf, with
+    // a checkpoint for each one that doesn't start where the one before it ends. The scanner,
+    // if there is one, finds where they start from char_start on in input_buf, and the return
+    // value is where it got to.
+    fn track_char_offsets(&mut self, decoded_len: usize, char_start: usize, end: usize) -> usize {
+        let source_len = self.decoder.source_len();
+        let scanner = match self.scanner.as_mut() {
+            Some(scanner) => scanner,
+            None => {
+                if source_len == SourceLen::Utf8 {
+                    self.char_offset += self.output_buf.len() - decoded_len;
+                } else {
+                    for c in self.output_buf[decoded_len..].chars() {
+                        self.char_offset += source_len.of(c);
+                    }
+                }
+                return end;
+            }
+        };
+
+        let mut char_start = char_start;
+        // The sequence the current char is from, and how many of its chars are still to come.
+        // Escape sequences count as part of the sequence after them.
+        let mut sequence_len = 0;
+        let mut sequence_chars = 0;
+        let mut in_step = true;
+        for (i, c) in self.output_buf[decoded_len..].char_indices() {
+            while in_step && sequence_chars == 0 {
+                let sequence_start = char_start + sequence_len;
+                match scanner.next_sequence(&self.input_buf[sequence_start..end]) {
+                    Some((len, chars)) => {
+                        sequence_len += len;
+                        sequence_chars = chars;
+                    }
+                    // Only malformed input should put the scanner out of step with the decoder,
+                    // and that doesn't get decoded, but if it does happen, char lengths are
+                    // the best guess there is
+                    None => in_step = false,
+                }
+            }
+            if !in_step {
+                self.char_offset += source_len.of(c);
+                continue;
+            }
+            let offset = self.input_offset + char_start;
+            if offset != self.char_offset {
+                self.checkpoints.push((decoded_len + i, offset));
+            }
+            self.char_offset = offset + source_len.of(c);
+            sequence_chars -= 1;
+            if sequence_chars == 0 {
+                char_start += sequence_len;
+                sequence_len = 0;
+            }
+        }
+        if !in_step {
+            return end;
+        }
+        // Escape sequences that no char is going to come after
+        if self.eof {
+            while let Some((len, 0)) = scanner.next_sequence(&self.input_buf[char_start..end]) {
+                char_start += len;
+            }
+        }
+        let offset = self.input_offset + char_start;
+        self.set_char_offset(offset);
+        char_start
+    }
+
+    // Validates, normalizes and maps the chars that were just decoded onto the end of output_buf,
+    // and the checkpoints that came with them. The first of them starts at start_offset.
+    fn process_decoded(
+        &mut self,
+        decoded_len: usize,
+        checkpoints_len: usize,
+        start_offset: usize,
+    ) -> Result<()> {
+        let source_len = self.decoder.source_len();
+        if !self.validate_chars && self.eol_normalizer.is_none() {
+            if let Some(offset_map) = self.offset_map.as_mut() {
+                let decoded = CharOffsets::new(
+                    &self.output_buf[decoded_len..],
+                    decoded_len,
+                    &self.checkpoints[checkpoints_len..],
+                    source_len,
+                    start_offset,
+                );
+                for (c, offset) in decoded {
+                    offset_map.advance(c, offset);
+                }
+            }
+            return Ok(());
+        }
+        let decoded = self.output_buf.split_off(decoded_len);
+        let checkpoints = self.checkpoints.split_off(checkpoints_len);
+        let end_offset = self.char_offset;
+        self.char_offset = start_offset;
+        for (c, offset) in CharOffsets::new(
+            &decoded,
+            decoded_len,
+            &checkpoints,
+            source_len,
+            start_offset,
+        ) {
+            self.push_char(c, offset)?;
+        }
+        self.set_char_offset(end_offset);
+        Ok(())
+    }
+
+    // Adds a decoded char, which starts offset bytes into the document, to output_buf
+    fn push_char(&mut self, c: char, offset: usize) -> Result<()> {
+        if self.validate_chars && !is_legal_char(c, self.version() == Some("1.1")) {
+            return Err(Error::IllegalChar {
+                c,
+                position: self.output_position(offset),
+            });
+        }
+        let source_c = c;
+        let c = match self.eol_normalizer.as_mut() {
+            Some(eol_normalizer) => match eol_normalizer.normalize_char(c) {
+                Some(c) => c,
+                None => return Ok(()),
+            },
+            None => c,
+        };
+        self.set_char_offset(offset);
+        self.output_buf.push(c);
+        self.char_offset += self.decoder.source_len().of(source_c);
+        self.map_output_char(c, offset);
+        Ok(())
+    }
+
+    // Notes that the next char added to output_buf starts offset bytes into the document
+    fn set_char_offset(&mut self, offset: usize) {
+        if offset != self.char_offset {
+            self.checkpoints.push((self.output_buf.len(), offset));
+            self.char_offset = offset;
+        }
+    }
+
+    // Follows the offset map past a char that was just added to output_buf
+    fn map_output_char(&mut self, c: char, offset: usize) {
+        if let Some(offset_map) = self.offset_map.as_mut() {
+            offset_map.advance(c, offset);
+        }
+    }
+
+    // The position of a char that's about to be added to output_buf, which starts offset bytes
+    // into the document. Only for errors, as it goes through all of the unread output.
+    fn output_position(&self, offset: usize) -> Position {
+        let mut line_tracker = self.line_tracker.clone();
+        line_tracker.advance_utf8(&self.output_buf.as_bytes()[self.tracked_pos..]);
+        line_tracker.position(offset)
+    }
+
+    // Empties output_buf, which has all been read, to make way for more output
+    fn clear_output(&mut self) {
+        self.output_buf.clear();
+        self.output_pos = 0;
+        self.checkpoints.clear();
+        self.checkpoints.push((0, self.char_offset));
+        self.tracked_pos = 0;
+        self.read_offset = self.char_offset;
+        self.next_checkpoint = 0;
+    }
+
+    // Follows the position past the chars that have been read all of since the last call
+    fn track_read(&mut self) {
+        let mut end = self.output_pos;
+        while !self.output_buf.is_char_boundary(end) {
+            end -= 1;
+        }
+        if end <= self.tracked_pos {
+            return;
+        }
+        let source_len = self.decoder.source_len();
+        let text = &self.output_buf[self.tracked_pos..end];
+        let checkpoints = &self.checkpoints[self.next_checkpoint..];
+        if source_len == SourceLen::Utf8 && checkpoints.first().map_or(true, |&(i, _)| i >= end) {
+            self.line_tracker.advance_utf8(text.as_bytes());
+            self.read_offset += text.len();
+        } else {
+            let mut chars = CharOffsets::new(
+                text,
+                self.tracked_pos,
+                checkpoints,
+                source_len,
+                self.read_offset,
+            );
+            for (c, _) in &mut chars {
+                self.line_tracker.advance(c);
+            }
+            self.read_offset = chars.offset;
+            self.next_checkpoint = self.checkpoints.len() - chars.checkpoints.len();
+        }
+        self.tracked_pos = end;
+    }
+
+    // An error from the wrapped reader, e.g. WouldBlock, leaves everything as it was, so that
+    // it can be retried
+    pub(crate) fn fill_output_buf(&mut self) -> Result<()> {
+        self.borrowed = false;
+        while self.output_pos >= self.output_buf.len() && !self.finished {
+            if !self.eof {
+                match self.fill_input_buf() {
+                    Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    // Decode whatever input there already is before waiting on more
+                    Err(Error::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => {
+                        if self.input_buf.is_empty() {
+                            return Err(Error::Io(e));
+                        }
+                        self.decode_input_buf()?;
+                        if self.output_buf.is_empty() {
+                            return Err(Error::Io(e));
+                        }
+                        continue;
+                    }
+                    result => result?,
+                }
+            }
+            self.decode_input_buf()?;
+        }
+        Ok(())
+    }
+
+    // Decoded data that hasn't been read yet
+    pub(crate) fn unread_output(&self) -> &[u8] {
+        &self.output_buf.as_bytes()[self.output_pos..]
+    }
+
+    pub(crate) fn consume_output(&mut self, amt: usize) {
+        self.output_pos = std::cmp::min(self.output_pos + amt, self.output_buf.len());
+        self.track_read();
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity
+    }
 
-    // Initialize the input_buf from the pre-buffered data
-    // if prebuf is bigger than the requested capacity, we'll increase the capacity to the size
-    // of the pre-buffered data
-    let mut input_buf: Vec<u8> = Vec::with_capacity(std::cmp::max(capacity, prebuf.len()));
-    input_buf.extend(prebuf);
+    // Appends all the decoded data that hasn't been read yet to output. Only for readers that
+    // are never read from through Read, whose reads can end partway through a char.
+    pub(crate) fn take_output(&mut self, output: &mut String) {
+        output.push_str(&self.output_buf[self.output_pos..]);
+        self.output_pos = self.output_buf.len();
+        self.track_read();
+    }
+}
+
+impl<R: BufRead> DecodingReader<R> {
+    /// Like `BufRead::fill_buf`, but returns decoded text, which is empty once the document is
+    /// done. When utf-8 input is passed through as it is (see `set_utf8_passthrough`), the text
+    /// is borrowed from the wrapped reader's buffer, rather than copied out of it. After a
+    /// `Read::read` that ended partway through a char, the text starts with the whole of that
+    /// char, which, as with `position`, doesn't count as read until all of it has been.
+    pub fn fill_str(&mut self) -> Result<&str> {
+        let borrowable_len = if self.output_pos >= self.output_buf.len()
+            && self.input_buf.is_empty()
+            && !self.eof
+            && self.passes_through()
+        {
+            let available = loop {
+                match self.inner.fill_buf() {
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    result => break result?,
+                }
+            };
+            // Nothing can be borrowed unless the first char is complete and valid
+            let complete_len = utf8_complete_len(available);
+            match std::str::from_utf8(&available[..std::cmp::min(complete_len, 4)]) {
+                Err(ref e) if e.valid_up_to() == 0 => 0,
+                _ => complete_len,
+            }
+        } else {
+            0
+        };
+        if borrowable_len > 0 {
+            self.check_size_limit_at(self.input_offset + borrowable_len)?;
+            self.borrowed = true;
+            let available = &self.inner.fill_buf()?[..borrowable_len];
+            return Ok(match std::str::from_utf8(available) {
+                Ok(text) => text,
+                // Malformed input is left for when it's reached
+                Err(e) => std::str::from_utf8(&available[..e.valid_up_to()]).unwrap_or_default(),
+            });
+        }
+
+        self.fill_output_buf()?;
+        while !self.output_buf.is_char_boundary(self.output_pos) {
+            self.output_pos -= 1;
+        }
+        Ok(&self.output_buf[self.output_pos..])
+    }
+
+    /// Marks `amt` bytes of the text from `fill_str` as read.
+    pub fn consume_str(&mut self, amt: usize) {
+        if !self.borrowed {
+            self.consume_output(amt);
+            return;
+        }
+        self.borrowed = false;
+        // The wrapped reader still has the borrowed text in its buffer
+        if let Ok(available) = self.inner.fill_buf() {
+            let consumed = &available[..std::cmp::min(amt, available.len())];
+            self.line_tracker.advance_utf8(consumed);
+        }
+        self.inner.consume(amt);
+        self.input_offset += amt;
+        self.char_offset = self.input_offset;
+        self.clear_output();
+    }
+}
+
+impl<R: Read> Read for DecodingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.fill_output_buf()?;
+        let available = self.unread_output();
+        let bytes_read = std::cmp::min(buf.len(), available.len());
+        buf[..bytes_read].copy_from_slice(&available[..bytes_read]);
+        self.consume_output(bytes_read);
+        Ok(bytes_read)
+    }
+}
 
-    CodecReadBuffer::for_encoding_with_initial_buffer(inner, &encoding_name, input_buf)
+impl<R: Read> BufRead for DecodingReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.fill_output_buf()?;
+        Ok(self.unread_output())
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.consume_output(amt);
+    }
 }
 
 #[cfg(test)]
 mod reader_tests {
     use super::*;
-    use std::io::Read;
+    use ebcdic;
+    use enc_detect::DetectionRule;
+    use position::Position;
 
     #[test]
     fn test_utf8() {
@@ -368,4 +1419,1890 @@ mod reader_tests {
             Err(e) => panic!("Failed initializing read buffer: {}", e),
         }
     }
+
+    #[test]
+    fn test_utf32le() {
+        // Test with no xmldecl, no encodingdecl
+        // Validation docs have the same text as the test docs, but always in utf-8, no bom
+        let utf32_validation = include_bytes!("../tests/validation/utf32le.xml").to_vec();
+
+        let utf32_bytes = include_bytes!("../tests/utf32le/doc.xml").to_vec();
+        match new(&utf32_bytes as &[u8]) {
+            Ok(mut decoding_reader) => {
+                let mut utf8_encoded_doc: String = String::new();
+                decoding_reader
+                    .read_to_string(&mut utf8_encoded_doc)
+                    .expect("Failed decoding input data");
+                assert_eq!(&utf32_validation, &utf8_encoded_doc.as_bytes());
+            }
+            Err(e) => panic!("Failed initializing read buffer: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_utf32le_xmldecl() {
+        // Test with xmldecl, no encodingdecl
+        // Validation docs have the same text as the test docs, but always in utf-8, no bom
+        let utf32_validation = include_bytes!("../tests/validation/utf32le_xmldecl.xml").to_vec();
+
+        let utf32_bytes = include_bytes!("../tests/utf32le/doc_xmldecl.xml").to_vec();
+        match new(&utf32_bytes as &[u8]) {
+            Ok(mut decoding_reader) => {
+                let mut utf8_encoded_doc: String = String::new();
+                decoding_reader
+                    .read_to_string(&mut utf8_encoded_doc)
+                    .expect("Failed decoding input data");
+                assert_eq!(&utf32_validation, &utf8_encoded_doc.as_bytes());
+            }
+            Err(e) => panic!("Failed initializing read buffer: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_utf32le_xmldecl_encodingdecl() {
+        // Test with xmldecl, encodingdecl
+        // Validation docs have the same text as the test docs, but always in utf-8, no bom
+        let utf32_validation =
+            include_bytes!("../tests/validation/utf32le_xmldecl_encodingdecl.xml").to_vec();
+
+        let utf32_bytes = include_bytes!("../tests/utf32le/doc_xmldecl_encodingdecl.xml").to_vec();
+        match new(&utf32_bytes as &[u8]) {
+            Ok(mut decoding_reader) => {
+                let mut utf8_encoded_doc: String = String::new();
+                decoding_reader
+                    .read_to_string(&mut utf8_encoded_doc)
+                    .expect("Failed decoding input data");
+                assert_eq!(&utf32_validation, &utf8_encoded_doc.as_bytes());
+            }
+            Err(e) => panic!("Failed initializing read buffer: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_utf32le_with_bom() {
+        // Test with no xmldecl, no encodingdecl
+        // Validation docs have the same text as the test docs, but always in utf-8, no bom
+        let utf32_validation = include_bytes!("../tests/validation/utf32le.xml").to_vec();
+
+        let utf32_with_bom_bytes = include_bytes!("../tests/utf32le_bom/doc.xml").to_vec();
+        match new(&utf32_with_bom_bytes as &[u8]) {
+            Ok(mut decoding_reader) => {
+                let mut utf8_encoded_doc: String = String::new();
+                decoding_reader
+                    .read_to_string(&mut utf8_encoded_doc)
+                    .expect("Failed decoding input data");
+                assert_eq!(&utf32_validation, &utf8_encoded_doc.as_bytes());
+            }
+            Err(e) => panic!("Failed initializing read buffer: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_utf32le_with_bom_xmldecl() {
+        // Test with xmldecl, no encodingdecl
+        // Validation docs have the same text as the test docs, but always in utf-8, no bom
+        let utf32_validation = include_bytes!("../tests/validation/utf32le_xmldecl.xml").to_vec();
+
+        let utf32_with_bom_bytes = include_bytes!("../tests/utf32le_bom/doc_xmldecl.xml").to_vec();
+        match new(&utf32_with_bom_bytes as &[u8]) {
+            Ok(mut decoding_reader) => {
+                let mut utf8_encoded_doc: String = String::new();
+                decoding_reader
+                    .read_to_string(&mut utf8_encoded_doc)
+                    .expect("Failed decoding input data");
+                assert_eq!(&utf32_validation, &utf8_encoded_doc.as_bytes());
+            }
+            Err(e) => panic!("Failed initializing read buffer: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_utf32le_with_bom_xmldecl_encodingdecl() {
+        // Test with xmldecl, encodingdecl
+        // Validation docs have the same text as the test docs, but always in utf-8, no bom
+        let utf32_validation =
+            include_bytes!("../tests/validation/utf32le_xmldecl_encodingdecl.xml").to_vec();
+
+        let utf32_with_bom_bytes =
+            include_bytes!("../tests/utf32le_bom/doc_xmldecl_encodingdecl.xml").to_vec();
+        match new(&utf32_with_bom_bytes as &[u8]) {
+            Ok(mut decoding_reader) => {
+                let mut utf8_encoded_doc: String = String::new();
+                decoding_reader
+                    .read_to_string(&mut utf8_encoded_doc)
+                    .expect("Failed decoding input data");
+                assert_eq!(&utf32_validation, &utf8_encoded_doc.as_bytes());
+            }
+            Err(e) => panic!("Failed initializing read buffer: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_utf32be() {
+        // Test with no xmldecl, no encodingdecl
+        // Validation docs have the same text as the test docs, but always in utf-8, no bom
+        let utf32_validation = include_bytes!("../tests/validation/utf32be.xml").to_vec();
+
+        let utf32_bytes = include_bytes!("../tests/utf32be/doc.xml").to_vec();
+        match new(&utf32_bytes as &[u8]) {
+            Ok(mut decoding_reader) => {
+                let mut utf8_encoded_doc: String = String::new();
+                decoding_reader
+                    .read_to_string(&mut utf8_encoded_doc)
+                    .expect("Failed decoding input data");
+                assert_eq!(&utf32_validation, &utf8_encoded_doc.as_bytes());
+            }
+            Err(e) => panic!("Failed initializing read buffer: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_utf32be_xmldecl() {
+        // Test with xmldecl, no encodingdecl
+        // Validation docs have the same text as the test docs, but always in utf-8, no bom
+        let utf32_validation = include_bytes!("../tests/validation/utf32be_xmldecl.xml").to_vec();
+
+        let utf32_bytes = include_bytes!("../tests/utf32be/doc_xmldecl.xml").to_vec();
+        match new(&utf32_bytes as &[u8]) {
+            Ok(mut decoding_reader) => {
+                let mut utf8_encoded_doc: String = String::new();
+                decoding_reader
+                    .read_to_string(&mut utf8_encoded_doc)
+                    .expect("Failed decoding input data");
+                assert_eq!(&utf32_validation, &utf8_encoded_doc.as_bytes());
+            }
+            Err(e) => panic!("Failed initializing read buffer: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_utf32be_xmldecl_encodingdecl() {
+        // Test with xmldecl, encodingdecl
+        // Validation docs have the same text as the test docs, but always in utf-8, no bom
+        let utf32_validation =
+            include_bytes!("../tests/validation/utf32be_xmldecl_encodingdecl.xml").to_vec();
+
+        let utf32_bytes = include_bytes!("../tests/utf32be/doc_xmldecl_encodingdecl.xml").to_vec();
+        match new(&utf32_bytes as &[u8]) {
+            Ok(mut decoding_reader) => {
+                let mut utf8_encoded_doc: String = String::new();
+                decoding_reader
+                    .read_to_string(&mut utf8_encoded_doc)
+                    .expect("Failed decoding input data");
+                assert_eq!(&utf32_validation, &utf8_encoded_doc.as_bytes());
+            }
+            Err(e) => panic!("Failed initializing read buffer: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_utf32be_with_bom() {
+        // Test with no xmldecl, no encodingdecl
+        // Validation docs have the same text as the test docs, but always in utf-8, no bom
+        let utf32_validation = include_bytes!("../tests/validation/utf32be.xml").to_vec();
+
+        let utf32_with_bom_bytes = include_bytes!("../tests/utf32be_bom/doc.xml").to_vec();
+        match new(&utf32_with_bom_bytes as &[u8]) {
+            Ok(mut decoding_reader) => {
+                let mut utf8_encoded_doc: String = String::new();
+                decoding_reader
+                    .read_to_string(&mut utf8_encoded_doc)
+                    .expect("Failed decoding input data");
+                assert_eq!(&utf32_validation, &utf8_encoded_doc.as_bytes());
+            }
+            Err(e) => panic!("Failed initializing read buffer: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_utf32be_with_bom_xmldecl() {
+        // Test with xmldecl, no encodingdecl
+        // Validation docs have the same text as the test docs, but always in utf-8, no bom
+        let utf32_validation = include_bytes!("../tests/validation/utf32be_xmldecl.xml").to_vec();
+
+        let utf32_with_bom_bytes = include_bytes!("../tests/utf32be_bom/doc_xmldecl.xml").to_vec();
+        match new(&utf32_with_bom_bytes as &[u8]) {
+            Ok(mut decoding_reader) => {
+                let mut utf8_encoded_doc: String = String::new();
+                decoding_reader
+                    .read_to_string(&mut utf8_encoded_doc)
+                    .expect("Failed decoding input data");
+                assert_eq!(&utf32_validation, &utf8_encoded_doc.as_bytes());
+            }
+            Err(e) => panic!("Failed initializing read buffer: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_utf32be_with_bom_xmldecl_encodingdecl() {
+        // Test with xmldecl, encodingdecl
+        // Validation docs have the same text as the test docs, but always in utf-8, no bom
+        let utf32_validation =
+            include_bytes!("../tests/validation/utf32be_xmldecl_encodingdecl.xml").to_vec();
+
+        let utf32_with_bom_bytes =
+            include_bytes!("../tests/utf32be_bom/doc_xmldecl_encodingdecl.xml").to_vec();
+        match new(&utf32_with_bom_bytes as &[u8]) {
+            Ok(mut decoding_reader) => {
+                let mut utf8_encoded_doc: String = String::new();
+                decoding_reader
+                    .read_to_string(&mut utf8_encoded_doc)
+                    .expect("Failed decoding input data");
+                assert_eq!(&utf32_validation, &utf8_encoded_doc.as_bytes());
+            }
+            Err(e) => panic!("Failed initializing read buffer: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_ibm037_xmldecl() {
+        // Test with xmldecl, no encodingdecl (defaults to ebcdic-cp-us)
+        // Validation docs have the same text as the test docs, but always in utf-8, no bom
+        let ebcdic_validation = include_bytes!("../tests/validation/ibm037_xmldecl.xml").to_vec();
+
+        let ebcdic_bytes = include_bytes!("../tests/ibm037/doc_xmldecl.xml").to_vec();
+        match new(&ebcdic_bytes as &[u8]) {
+            Ok(mut decoding_reader) => {
+                let mut utf8_encoded_doc: String = String::new();
+                decoding_reader
+                    .read_to_string(&mut utf8_encoded_doc)
+                    .expect("Failed decoding input data");
+                assert_eq!(&ebcdic_validation, &utf8_encoded_doc.as_bytes());
+            }
+            Err(e) => panic!("Failed initializing read buffer: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_ibm037_xmldecl_encodingdecl() {
+        // Test with xmldecl, encodingdecl
+        // Validation docs have the same text as the test docs, but always in utf-8, no bom
+        let ebcdic_validation =
+            include_bytes!("../tests/validation/ibm037_xmldecl_encodingdecl.xml").to_vec();
+
+        let ebcdic_bytes = include_bytes!("../tests/ibm037/doc_xmldecl_encodingdecl.xml").to_vec();
+        match new(&ebcdic_bytes as &[u8]) {
+            Ok(mut decoding_reader) => {
+                let mut utf8_encoded_doc: String = String::new();
+                decoding_reader
+                    .read_to_string(&mut utf8_encoded_doc)
+                    .expect("Failed decoding input data");
+                assert_eq!(&ebcdic_validation, &utf8_encoded_doc.as_bytes());
+            }
+            Err(e) => panic!("Failed initializing read buffer: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_ibm500_xmldecl_encodingdecl() {
+        // Test with xmldecl, encodingdecl
+        // Validation docs have the same text as the test docs, but always in utf-8, no bom
+        let ebcdic_validation =
+            include_bytes!("../tests/validation/ibm500_xmldecl_encodingdecl.xml").to_vec();
+
+        let ebcdic_bytes = include_bytes!("../tests/ibm500/doc_xmldecl_encodingdecl.xml").to_vec();
+        match new(&ebcdic_bytes as &[u8]) {
+            Ok(mut decoding_reader) => {
+                let mut utf8_encoded_doc: String = String::new();
+                decoding_reader
+                    .read_to_string(&mut utf8_encoded_doc)
+                    .expect("Failed decoding input data");
+                assert_eq!(&ebcdic_validation, &utf8_encoded_doc.as_bytes());
+            }
+            Err(e) => panic!("Failed initializing read buffer: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_ibm1047_xmldecl_encodingdecl() {
+        // Test with xmldecl, encodingdecl
+        // Validation docs have the same text as the test docs, but always in utf-8, no bom
+        let ebcdic_validation =
+            include_bytes!("../tests/validation/ibm1047_xmldecl_encodingdecl.xml").to_vec();
+
+        let ebcdic_bytes = include_bytes!("../tests/ibm1047/doc_xmldecl_encodingdecl.xml").to_vec();
+        match new(&ebcdic_bytes as &[u8]) {
+            Ok(mut decoding_reader) => {
+                let mut utf8_encoded_doc: String = String::new();
+                decoding_reader
+                    .read_to_string(&mut utf8_encoded_doc)
+                    .expect("Failed decoding input data");
+                assert_eq!(&ebcdic_validation, &utf8_encoded_doc.as_bytes());
+            }
+            Err(e) => panic!("Failed initializing read buffer: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_utf_ebcdic_unsupported() {
+        // UTF-EBCDIC is out of scope, whether it's given away by its BOM or declared
+        let mut utf_ebcdic_bytes = vec![0xDD, 0x73, 0x66, 0x73];
+        utf_ebcdic_bytes
+            .extend(include_bytes!("../tests/ibm1047/doc_xmldecl_encodingdecl.xml").iter());
+        match new(&utf_ebcdic_bytes as &[u8]) {
+            Err(Error::UnsupportedEncoding(name)) => assert_eq!(name, "UTF-EBCDIC"),
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("Accepted a UTF-EBCDIC document"),
+        }
+
+        let encoding_table = ebcdic::for_label("ibm037")
+            .expect("IBM037 is supported")
+            .encoding_table();
+        let ebcdic_bytes: Vec<u8> = "<?xml version='1.0' encoding='UTF-EBCDIC'?><doc/>"
+            .bytes()
+            .map(|b| encoding_table[b as usize])
+            .collect();
+        match new(&ebcdic_bytes as &[u8]) {
+            Err(Error::UnsupportedEncoding(name)) => assert_eq!(name, "UTF-EBCDIC"),
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("Accepted a UTF-EBCDIC document"),
+        }
+    }
+
+    #[test]
+    fn test_iso_8859_1_xmldecl_encodingdecl() {
+        // Test with xmldecl, encodingdecl
+        // Validation docs have the same text as the test docs, but always in utf-8, no bom
+        let legacy_validation =
+            include_bytes!("../tests/validation/iso-8859-1_xmldecl_encodingdecl.xml").to_vec();
+
+        let legacy_bytes =
+            include_bytes!("../tests/iso-8859-1/doc_xmldecl_encodingdecl.xml").to_vec();
+        match new(&legacy_bytes as &[u8]) {
+            Ok(mut decoding_reader) => {
+                let mut utf8_encoded_doc: String = String::new();
+                decoding_reader
+                    .read_to_string(&mut utf8_encoded_doc)
+                    .expect("Failed decoding input data");
+                assert_eq!(&legacy_validation, &utf8_encoded_doc.as_bytes());
+            }
+            Err(e) => panic!("Failed initializing read buffer: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_koi8_r_xmldecl_encodingdecl() {
+        // Test with xmldecl, encodingdecl
+        // Validation docs have the same text as the test docs, but always in utf-8, no bom
+        let legacy_validation =
+            include_bytes!("../tests/validation/koi8-r_xmldecl_encodingdecl.xml").to_vec();
+
+        let legacy_bytes = include_bytes!("../tests/koi8-r/doc_xmldecl_encodingdecl.xml").to_vec();
+        match new(&legacy_bytes as &[u8]) {
+            Ok(mut decoding_reader) => {
+                let mut utf8_encoded_doc: String = String::new();
+                decoding_reader
+                    .read_to_string(&mut utf8_encoded_doc)
+                    .expect("Failed decoding input data");
+                assert_eq!(&legacy_validation, &utf8_encoded_doc.as_bytes());
+            }
+            Err(e) => panic!("Failed initializing read buffer: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_windows_1251_xmldecl_encodingdecl() {
+        // Test with xmldecl, encodingdecl
+        // Validation docs have the same text as the test docs, but always in utf-8, no bom
+        let legacy_validation =
+            include_bytes!("../tests/validation/windows-1251_xmldecl_encodingdecl.xml").to_vec();
+
+        let legacy_bytes =
+            include_bytes!("../tests/windows-1251/doc_xmldecl_encodingdecl.xml").to_vec();
+        match new(&legacy_bytes as &[u8]) {
+            Ok(mut decoding_reader) => {
+                let mut utf8_encoded_doc: String = String::new();
+                decoding_reader
+                    .read_to_string(&mut utf8_encoded_doc)
+                    .expect("Failed decoding input data");
+                assert_eq!(&legacy_validation, &utf8_encoded_doc.as_bytes());
+            }
+            Err(e) => panic!("Failed initializing read buffer: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_shift_jis_xmldecl_encodingdecl() {
+        // Test with xmldecl, encodingdecl
+        // Validation docs have the same text as the test docs, but always in utf-8, no bom
+        let legacy_validation =
+            include_bytes!("../tests/validation/shift_jis_xmldecl_encodingdecl.xml").to_vec();
+
+        let legacy_bytes =
+            include_bytes!("../tests/shift_jis/doc_xmldecl_encodingdecl.xml").to_vec();
+        match new(&legacy_bytes as &[u8]) {
+            Ok(mut decoding_reader) => {
+                let mut utf8_encoded_doc: String = String::new();
+                decoding_reader
+                    .read_to_string(&mut utf8_encoded_doc)
+                    .expect("Failed decoding input data");
+                assert_eq!(&legacy_validation, &utf8_encoded_doc.as_bytes());
+            }
+            Err(e) => panic!("Failed initializing read buffer: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_euc_jp_xmldecl_encodingdecl() {
+        // Test with xmldecl, encodingdecl
+        // Validation docs have the same text as the test docs, but always in utf-8, no bom
+        let legacy_validation =
+            include_bytes!("../tests/validation/euc-jp_xmldecl_encodingdecl.xml").to_vec();
+
+        let legacy_bytes = include_bytes!("../tests/euc-jp/doc_xmldecl_encodingdecl.xml").to_vec();
+        match new(&legacy_bytes as &[u8]) {
+            Ok(mut decoding_reader) => {
+                let mut utf8_encoded_doc: String = String::new();
+                decoding_reader
+                    .read_to_string(&mut utf8_encoded_doc)
+                    .expect("Failed decoding input data");
+                assert_eq!(&legacy_validation, &utf8_encoded_doc.as_bytes());
+            }
+            Err(e) => panic!("Failed initializing read buffer: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_iso_2022_jp_xmldecl_encodingdecl() {
+        // Test with xmldecl, encodingdecl
+        // Validation docs have the same text as the test docs, but always in utf-8, no bom
+        let legacy_validation =
+            include_bytes!("../tests/validation/iso-2022-jp_xmldecl_encodingdecl.xml").to_vec();
+
+        let legacy_bytes =
+            include_bytes!("../tests/iso-2022-jp/doc_xmldecl_encodingdecl.xml").to_vec();
+        match new(&legacy_bytes as &[u8]) {
+            Ok(mut decoding_reader) => {
+                let mut utf8_encoded_doc: String = String::new();
+                decoding_reader
+                    .read_to_string(&mut utf8_encoded_doc)
+                    .expect("Failed decoding input data");
+                assert_eq!(&legacy_validation, &utf8_encoded_doc.as_bytes());
+            }
+            Err(e) => panic!("Failed initializing read buffer: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_euc_kr_xmldecl_encodingdecl() {
+        // Test with xmldecl, encodingdecl
+        // Validation docs have the same text as the test docs, but always in utf-8, no bom
+        let legacy_validation =
+            include_bytes!("../tests/validation/euc-kr_xmldecl_encodingdecl.xml").to_vec();
+
+        let legacy_bytes = include_bytes!("../tests/euc-kr/doc_xmldecl_encodingdecl.xml").to_vec();
+        match new(&legacy_bytes as &[u8]) {
+            Ok(mut decoding_reader) => {
+                let mut utf8_encoded_doc: String = String::new();
+                decoding_reader
+                    .read_to_string(&mut utf8_encoded_doc)
+                    .expect("Failed decoding input data");
+                assert_eq!(&legacy_validation, &utf8_encoded_doc.as_bytes());
+            }
+            Err(e) => panic!("Failed initializing read buffer: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_gbk_xmldecl_encodingdecl() {
+        // Test with xmldecl, encodingdecl
+        // Validation docs have the same text as the test docs, but always in utf-8, no bom
+        let legacy_validation =
+            include_bytes!("../tests/validation/gbk_xmldecl_encodingdecl.xml").to_vec();
+
+        let legacy_bytes = include_bytes!("../tests/gbk/doc_xmldecl_encodingdecl.xml").to_vec();
+        match new(&legacy_bytes as &[u8]) {
+            Ok(mut decoding_reader) => {
+                let mut utf8_encoded_doc: String = String::new();
+                decoding_reader
+                    .read_to_string(&mut utf8_encoded_doc)
+                    .expect("Failed decoding input data");
+                assert_eq!(&legacy_validation, &utf8_encoded_doc.as_bytes());
+            }
+            Err(e) => panic!("Failed initializing read buffer: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_gb18030_xmldecl_encodingdecl() {
+        // Test with xmldecl, encodingdecl
+        // Validation docs have the same text as the test docs, but always in utf-8, no bom
+        let legacy_validation =
+            include_bytes!("../tests/validation/gb18030_xmldecl_encodingdecl.xml").to_vec();
+
+        let legacy_bytes = include_bytes!("../tests/gb18030/doc_xmldecl_encodingdecl.xml").to_vec();
+        match new(&legacy_bytes as &[u8]) {
+            Ok(mut decoding_reader) => {
+                let mut utf8_encoded_doc: String = String::new();
+                decoding_reader
+                    .read_to_string(&mut utf8_encoded_doc)
+                    .expect("Failed decoding input data");
+                assert_eq!(&legacy_validation, &utf8_encoded_doc.as_bytes());
+            }
+            Err(e) => panic!("Failed initializing read buffer: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_big5_xmldecl_encodingdecl() {
+        // Test with xmldecl, encodingdecl
+        // Validation docs have the same text as the test docs, but always in utf-8, no bom
+        let legacy_validation =
+            include_bytes!("../tests/validation/big5_xmldecl_encodingdecl.xml").to_vec();
+
+        let legacy_bytes = include_bytes!("../tests/big5/doc_xmldecl_encodingdecl.xml").to_vec();
+        match new(&legacy_bytes as &[u8]) {
+            Ok(mut decoding_reader) => {
+                let mut utf8_encoded_doc: String = String::new();
+                decoding_reader
+                    .read_to_string(&mut utf8_encoded_doc)
+                    .expect("Failed decoding input data");
+                assert_eq!(&legacy_validation, &utf8_encoded_doc.as_bytes());
+            }
+            Err(e) => panic!("Failed initializing read buffer: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_legacy_matrix_xmldecl_encodingdecl() {
+        // Every ASCII-compatible single-byte encoding that's allowed to follow a BOM-less '<?xm',
+        // along with some text that isn't ASCII in that encoding
+        let legacy_docs = [
+            ("ibm866", "Жж"),
+            ("iso-8859-2", "Łł"),
+            ("iso-8859-3", "Ħħ"),
+            ("iso-8859-4", "Ķķ"),
+            ("iso-8859-5", "Жж"),
+            ("iso-8859-6", "ع"),
+            ("iso-8859-7", "Ωω"),
+            ("iso-8859-8", "א"),
+            ("iso-8859-8-i", "א"),
+            ("iso-8859-10", "Ŋŋ"),
+            ("iso-8859-13", "Łł"),
+            ("iso-8859-14", "Ŵŵ"),
+            ("iso-8859-15", "€"),
+            ("iso-8859-16", "Șș"),
+            ("koi8-r", "Жж"),
+            ("koi8-u", "Її"),
+            ("macintosh", "Ëé"),
+            ("windows-874", "ก"),
+            ("windows-1250", "Łł"),
+            ("windows-1251", "Жж"),
+            ("windows-1252", "Ë€"),
+            ("windows-1253", "Ωω"),
+            ("windows-1254", "Ğğ"),
+            ("windows-1255", "א"),
+            ("windows-1256", "ع"),
+            ("windows-1257", "Łł"),
+            ("windows-1258", "Ơơ"),
+            ("x-mac-cyrillic", "Жж"),
+            ("latin1", "Ë"),
+        ];
+        for &(encoding_name, text) in legacy_docs.iter() {
+            let validation = format!(
+                "<?xml version='1.0' encoding='{}'?>\n<doc>{}</doc>\n",
+                encoding_name, text
+            );
+            let (legacy_bytes, _, unmappable) =
+                encoding_rs::Encoding::for_label(encoding_name.as_bytes())
+                    .expect("Unknown encoding label")
+                    .encode(&validation);
+            assert!(!unmappable, "{} can't represent {}", encoding_name, text);
+            assert_ne!(validation.as_bytes(), &legacy_bytes[..]);
+
+            match new(&legacy_bytes as &[u8]) {
+                Ok(mut decoding_reader) => {
+                    let mut utf8_encoded_doc: String = String::new();
+                    decoding_reader
+                        .read_to_string(&mut utf8_encoded_doc)
+                        .expect("Failed decoding input data");
+                    assert_eq!(validation, utf8_encoded_doc);
+                }
+                Err(e) => panic!(
+                    "Failed initializing read buffer for {}: {}",
+                    encoding_name, e
+                ),
+            }
+        }
+    }
+
+    #[test]
+    fn test_incompatible_encodingdecl() {
+        // A document that looks ASCII-compatible can't declare a multi-byte encoding
+        let utf8_bytes = b"<?xml version='1.0' encoding='utf-16'?>\n<doc/>\n".to_vec();
+        assert!(new(&utf8_bytes as &[u8]).is_err());
+    }
+
+    #[test]
+    fn test_detection_report_bom() {
+        let utf16le_bytes = include_bytes!("../tests/utf16le_bom/doc_xmldecl.xml").to_vec();
+        let decoding_reader =
+            new(&utf16le_bytes as &[u8]).expect("Failed initializing read buffer");
+        let report = decoding_reader.detection_report();
+        assert_eq!(report.bom, Some("utf-16le".to_string()));
+        assert!(report.has_xml_decl);
+        assert_eq!(report.declared_encoding, None);
+        assert_eq!(report.suggested_encoding, None);
+        assert_eq!(report.encoding, "utf-16le");
+        assert_eq!(report.rule, DetectionRule::ByteOrderMark);
+    }
+
+    #[test]
+    fn test_detection_report_first_characters() {
+        let utf16be_bytes =
+            include_bytes!("../tests/utf16be/doc_xmldecl_encodingdecl.xml").to_vec();
+        let decoding_reader =
+            new(&utf16be_bytes as &[u8]).expect("Failed initializing read buffer");
+        let report = decoding_reader.detection_report();
+        assert_eq!(report.bom, None);
+        assert!(report.has_xml_decl);
+        assert_eq!(report.declared_encoding, Some("utf-16be".to_string()));
+        assert_eq!(report.encoding, "utf-16be");
+        assert_eq!(report.rule, DetectionRule::FirstCharacters);
+    }
+
+    #[test]
+    fn test_detection_report_encoding_declaration() {
+        let legacy_bytes = include_bytes!("../tests/koi8-r/doc_xmldecl_encodingdecl.xml").to_vec();
+        let decoding_reader = with_capacity_and_input_encoding(
+            &legacy_bytes as &[u8],
+            DEFAULT_BUF_SIZE,
+            Some("windows-1251".to_string()),
+        )
+        .expect("Failed initializing read buffer");
+        let report = decoding_reader.detection_report();
+        assert_eq!(report.bom, None);
+        assert!(report.has_xml_decl);
+        assert_eq!(report.declared_encoding, Some("koi8-r".to_string()));
+        assert_eq!(report.suggested_encoding, Some("windows-1251".to_string()));
+        assert_eq!(report.encoding, "koi8-r");
+        assert_eq!(report.rule, DetectionRule::EncodingDeclaration);
+    }
+
+    #[test]
+    fn test_detection_report_external_information() {
+        let legacy_bytes = b"<doc>\xC6\xE6</doc>\n".to_vec();
+        let decoding_reader = with_capacity_and_input_encoding(
+            &legacy_bytes as &[u8],
+            DEFAULT_BUF_SIZE,
+            Some("windows-1251".to_string()),
+        )
+        .expect("Failed initializing read buffer");
+        let report = decoding_reader.detection_report();
+        assert!(!report.has_xml_decl);
+        assert_eq!(report.declared_encoding, None);
+        assert_eq!(report.encoding, "windows-1251");
+        assert_eq!(report.rule, DetectionRule::ExternalInformation);
+    }
+
+    #[test]
+    fn test_detection_report_utf8_default() {
+        let utf8_bytes = include_bytes!("../tests/utf8/doc.xml").to_vec();
+        let decoding_reader = new(&utf8_bytes as &[u8]).expect("Failed initializing read buffer");
+        let report = decoding_reader.detection_report();
+        assert_eq!(report.bom, None);
+        assert!(!report.has_xml_decl);
+        assert_eq!(report.encoding, "utf-8");
+        assert_eq!(report.rule, DetectionRule::Utf8Default);
+    }
+
+    #[test]
+    fn test_builder() {
+        let legacy_bytes =
+            b"<?xml version='1.0' encoding='koi8-r'?>\r\n<doc>\xC6\xE6\xFF</doc>".to_vec();
+        let mut decoding_reader = ReaderBuilder::new()
+            .capacity(2)
+            .suggested_encoding("windows-1251")
+            .suggested_encoding_policy(SuggestedEncodingPolicy::Override)
+            .xml_decl_handling(XmlDeclHandling::Strip)
+            .eol_normalization(true)
+            .build(&legacy_bytes as &[u8])
+            .expect("Failed initializing read buffer");
+        let report = decoding_reader.detection_report();
+        assert_eq!(report.declared_encoding, Some("koi8-r".to_string()));
+        assert_eq!(report.encoding, "windows-1251");
+        assert_eq!(report.rule, DetectionRule::ExternalInformation);
+        let mut utf8_encoded_doc: String = String::new();
+        decoding_reader
+            .read_to_string(&mut utf8_encoded_doc)
+            .expect("Failed decoding input data");
+        assert_eq!(utf8_encoded_doc, "\n<doc>\u{416}\u{436}\u{44f}</doc>");
+
+        // A capacity of 0 still reads the whole document
+        let mut decoding_reader = ReaderBuilder::new()
+            .capacity(0)
+            .build(&legacy_bytes as &[u8])
+            .expect("Failed initializing read buffer");
+        let mut utf8_encoded_doc: String = String::new();
+        decoding_reader
+            .read_to_string(&mut utf8_encoded_doc)
+            .expect("Failed decoding input data");
+        assert_eq!(
+            utf8_encoded_doc,
+            "<?xml version='1.0' encoding='koi8-r'?>\r\n<doc>\u{444}\u{424}\u{42a}</doc>"
+        );
+
+        // Without the override, the declaration wins
+        let decoding_reader = ReaderBuilder::new()
+            .suggested_encoding("windows-1251")
+            .build(&legacy_bytes as &[u8])
+            .expect("Failed initializing read buffer");
+        assert_eq!(decoding_reader.detection_report().encoding, "koi8-r");
+
+        // A BOM wins even over an overriding suggestion
+        let utf16le_bytes = include_bytes!("../tests/utf16le_bom/doc_xmldecl.xml").to_vec();
+        let decoding_reader = ReaderBuilder::new()
+            .suggested_encoding("windows-1251")
+            .suggested_encoding_policy(SuggestedEncodingPolicy::Override)
+            .build(&utf16le_bytes as &[u8])
+            .expect("Failed initializing read buffer");
+        assert_eq!(decoding_reader.detection_report().encoding, "utf-16le");
+
+        // but the suggestion has to fit the first characters
+        let utf16be_bytes = include_bytes!("../tests/utf16be/doc_xmldecl.xml").to_vec();
+        let mut builder = ReaderBuilder::new();
+        builder.suggested_encoding_policy(SuggestedEncodingPolicy::Override);
+        builder.suggested_encoding("utf-16");
+        match builder.build(&utf16be_bytes as &[u8]) {
+            Ok(decoding_reader) => {
+                assert_eq!(decoding_reader.detection_report().encoding, "utf-16be")
+            }
+            Err(e) => panic!("Failed initializing read buffer: {}", e),
+        }
+        builder.suggested_encoding("windows-1251");
+        match builder.build(&utf16be_bytes as &[u8]) {
+            Err(Error::SuggestedEncodingConflict {
+                suggested,
+                detected,
+            }) => {
+                assert_eq!(suggested, "windows-1251");
+                assert_eq!(detected, "utf-16be");
+            }
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("Accepted a suggestion that contradicts the first characters"),
+        }
+    }
+
+    fn build_with_policy(
+        doc: &[u8],
+        suggested: &str,
+        policy: SuggestedEncodingPolicy,
+    ) -> Result<String> {
+        ReaderBuilder::new()
+            .suggested_encoding(suggested)
+            .suggested_encoding_policy(policy)
+            .build(doc)
+            .map(|decoding_reader| decoding_reader.detection_report().encoding.clone())
+    }
+
+    #[test]
+    fn test_suggested_encoding_policy() {
+        let koi8_r_bytes = include_bytes!("../tests/koi8-r/doc_xmldecl_encodingdecl.xml").to_vec();
+        let utf16be_bytes = include_bytes!("../tests/utf16be_bom/doc_xmldecl.xml").to_vec();
+        let utf8_bytes = include_bytes!("../tests/utf8/doc.xml").to_vec();
+        let policies = [
+            SuggestedEncodingPolicy::Fallback,
+            SuggestedEncodingPolicy::Override,
+            SuggestedEncodingPolicy::RejectConflict,
+        ];
+        for policy in &policies {
+            // Suggestions that agree with the document are never a problem
+            assert_eq!(
+                build_with_policy(&koi8_r_bytes, "KOI8-R", *policy).ok(),
+                Some("koi8-r".to_string())
+            );
+            assert_eq!(
+                build_with_policy(&utf16be_bytes, "utf-16", *policy).ok(),
+                Some("utf-16be".to_string())
+            );
+            // and neither is a document that doesn't say
+            assert_eq!(
+                build_with_policy(&utf8_bytes, "windows-1251", *policy).ok(),
+                Some("windows-1251".to_string())
+            );
+        }
+
+        assert_eq!(
+            build_with_policy(
+                &koi8_r_bytes,
+                "windows-1251",
+                SuggestedEncodingPolicy::Fallback
+            )
+            .ok(),
+            Some("koi8-r".to_string())
+        );
+        assert_eq!(
+            build_with_policy(
+                &koi8_r_bytes,
+                "windows-1251",
+                SuggestedEncodingPolicy::Override
+            )
+            .ok(),
+            Some("windows-1251".to_string())
+        );
+        match build_with_policy(
+            &koi8_r_bytes,
+            "windows-1251",
+            SuggestedEncodingPolicy::RejectConflict,
+        ) {
+            Err(Error::SuggestedEncodingConflict {
+                suggested,
+                detected,
+            }) => {
+                assert_eq!(suggested, "windows-1251");
+                assert_eq!(detected, "koi8-r");
+            }
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("Accepted a suggestion that contradicts the declaration"),
+        }
+        match build_with_policy(
+            &utf16be_bytes,
+            "utf-16le",
+            SuggestedEncodingPolicy::RejectConflict,
+        ) {
+            Err(Error::SuggestedEncodingConflict { .. }) => {}
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("Accepted a suggestion that contradicts the BOM"),
+        }
+
+        // A declaration without an encoding leaves the document in utf-8
+        let utf8_xml_decl_bytes = b"<?xml version=\"1.0\"?><doc/>".to_vec();
+        assert_eq!(
+            build_with_policy(
+                &utf8_xml_decl_bytes,
+                "UTF-8",
+                SuggestedEncodingPolicy::RejectConflict
+            )
+            .ok(),
+            Some("utf-8".to_string())
+        );
+        match build_with_policy(
+            &utf8_xml_decl_bytes,
+            "iso-8859-1",
+            SuggestedEncodingPolicy::RejectConflict,
+        ) {
+            Err(Error::SuggestedEncodingConflict {
+                suggested,
+                detected,
+            }) => {
+                assert_eq!(suggested, "iso-8859-1");
+                assert_eq!(detected, "utf-8");
+            }
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("Accepted a suggestion that contradicts the utf-8 default"),
+        }
+    }
+
+    #[test]
+    fn test_content_type() {
+        let koi8_r_bytes = include_bytes!("../tests/koi8-r/doc_xmldecl_encodingdecl.xml").to_vec();
+        let decoding_reader = ReaderBuilder::new()
+            .content_type("application/xml; charset=windows-1251")
+            .build(&koi8_r_bytes as &[u8])
+            .expect("Failed initializing read buffer");
+        assert_eq!(decoding_reader.detection_report().encoding, "windows-1251");
+
+        // application/xml without a charset leaves it to the document
+        let decoding_reader = ReaderBuilder::new()
+            .content_type("application/xml")
+            .build(&koi8_r_bytes as &[u8])
+            .expect("Failed initializing read buffer");
+        assert_eq!(decoding_reader.detection_report().encoding, "koi8-r");
+
+        // text/xml's us-ascii default mustn't override the declaration
+        let utf8_bytes = "<?xml version='1.0' encoding='utf-8'?><doc>\u{e9}</doc>".as_bytes();
+        let mut decoding_reader = ReaderBuilder::new()
+            .content_type("text/xml")
+            .build(utf8_bytes)
+            .expect("Failed initializing read buffer");
+        assert_eq!(decoding_reader.detection_report().encoding, "utf-8");
+        let mut utf8_encoded_doc = String::new();
+        decoding_reader
+            .read_to_string(&mut utf8_encoded_doc)
+            .expect("Failed decoding input data");
+        assert_eq!(utf8_encoded_doc.as_bytes(), utf8_bytes);
+
+        // but it's still used for a document that doesn't say
+        let decoding_reader = ReaderBuilder::new()
+            .content_type("text/xml")
+            .build(&b"<doc/>"[..])
+            .expect("Failed initializing read buffer");
+        assert_eq!(decoding_reader.detection_report().encoding, "windows-1252");
+    }
+
+    #[test]
+    fn test_size_limit() {
+        let utf8_bytes = b"<?xml version='1.0'?><doc/>".to_vec();
+        for capacity in &[1, DEFAULT_BUF_SIZE] {
+            let mut decoding_reader = ReaderBuilder::new()
+                .capacity(*capacity)
+                .size_limit(Some(utf8_bytes.len()))
+                .build(&utf8_bytes as &[u8])
+                .expect("Failed initializing read buffer");
+            let mut utf8_encoded_doc: String = String::new();
+            decoding_reader
+                .read_to_string(&mut utf8_encoded_doc)
+                .expect("Failed decoding input data");
+            assert_eq!(utf8_encoded_doc.as_bytes(), &utf8_bytes[..]);
+
+            let mut decoding_reader = ReaderBuilder::new()
+                .capacity(*capacity)
+                .size_limit(Some(utf8_bytes.len() - 1))
+                .build(&utf8_bytes as &[u8])
+                .expect("Failed initializing read buffer");
+            let mut utf8_encoded_doc: String = String::new();
+            let e = decoding_reader
+                .read_to_string(&mut utf8_encoded_doc)
+                .expect_err("Read past the size limit");
+            match e.get_ref().and_then(|e| e.downcast_ref::<Error>()) {
+                Some(Error::SizeLimitExceeded { limit }) => assert_eq!(*limit, 26),
+                _ => panic!("Unexpected error: {}", e),
+            }
+        }
+
+        // The xml declaration alone is longer than the limit
+        match ReaderBuilder::new()
+            .size_limit(Some(4))
+            .build(&utf8_bytes as &[u8])
+        {
+            Err(Error::SizeLimitExceeded { limit }) => assert_eq!(limit, 4),
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("Read past the size limit"),
+        }
+    }
+
+    #[test]
+    fn test_error_no_bom_no_decl() {
+        let utf16le_bytes = include_bytes!("../tests/utf16le/doc.xml").to_vec();
+        match new(&utf16le_bytes as &[u8]) {
+            Err(Error::NoBomNoDecl) => {}
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("Detected an encoding without a BOM or xml declaration"),
+        }
+    }
+
+    #[test]
+    fn test_error_unsupported_encoding() {
+        let utf8_bytes = b"<?xml version='1.0' encoding='x-no-such-encoding'?>\n<doc/>\n".to_vec();
+        match new(&utf8_bytes as &[u8]) {
+            Err(Error::UnsupportedEncoding(name)) => assert_eq!(name, "x-no-such-encoding"),
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("Accepted an unknown encoding"),
+        }
+    }
+
+    #[test]
+    fn test_error_bom_decl_conflict() {
+        let mut utf8_bytes = vec![0xEF, 0xBB, 0xBF];
+        utf8_bytes.extend(b"<?xml version='1.0' encoding='koi8-r'?>\n<doc/>\n");
+        match new(&utf8_bytes as &[u8]) {
+            Err(Error::BomDeclConflict { detected, declared }) => {
+                assert_eq!(detected, "utf-8");
+                assert_eq!(declared, "koi8-r");
+            }
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("Accepted a declaration that contradicts the BOM"),
+        }
+    }
+
+    #[test]
+    fn test_error_malformed_xml_decl() {
+        let utf8_bytes = b"<?xml version='1.0' encoding=utf-8?>\n<doc/>\n".to_vec();
+        match new(&utf8_bytes as &[u8]) {
+            Err(Error::MalformedXmlDecl(_)) => {}
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("Accepted an unquoted encoding name"),
+        }
+    }
+
+    #[test]
+    fn test_error_malformed_input() {
+        let mut utf8_bytes = vec![0xEF, 0xBB, 0xBF];
+        utf8_bytes.extend(b"<doc>\xFF</doc>\n");
+        let mut decoding_reader =
+            new(&utf8_bytes as &[u8]).expect("Failed initializing read buffer");
+        let mut utf8_encoded_doc: String = String::new();
+        let e = decoding_reader
+            .read_to_string(&mut utf8_encoded_doc)
+            .expect_err("Decoded malformed input");
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        match e.get_ref().and_then(|e| e.downcast_ref::<Error>()) {
+            Some(Error::MalformedInput { position }) => assert_eq!(
+                *position,
+                Position {
+                    offset: 8,
+                    char_offset: 5,
+                    line: 1,
+                    column: 6
+                }
+            ),
+            _ => panic!("Unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_error_malformed_input_position() {
+        // A lone low surrogate on the third line
+        let mut utf16le_bytes = vec![0xFF, 0xFE];
+        for c in "<doc>\r\n<a>\u{e9}\n".encode_utf16() {
+            utf16le_bytes.extend_from_slice(&c.to_le_bytes());
+        }
+        utf16le_bytes.extend_from_slice(&[0x00, 0xDC]);
+        for capacity in &[1, 3, DEFAULT_BUF_SIZE] {
+            let mut decoding_reader = with_capacity(&utf16le_bytes as &[u8], *capacity)
+                .expect("Failed initializing read buffer");
+            let mut utf8_encoded_doc: String = String::new();
+            let e = decoding_reader
+                .read_to_string(&mut utf8_encoded_doc)
+                .expect_err("Decoded malformed input");
+            match e.get_ref().and_then(|e| e.downcast_ref::<Error>()) {
+                Some(Error::MalformedInput { position }) => assert_eq!(
+                    *position,
+                    Position {
+                        offset: 26,
+                        char_offset: 12,
+                        line: 3,
+                        column: 1
+                    }
+                ),
+                _ => panic!("Unexpected error: {}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn test_zero_capacity() {
+        // Reads into no room at all mustn't pass for the end of the input
+        let mut decoding_reader =
+            with_capacity(&b"<a>hello</a>"[..], 0).expect("Failed initializing read buffer");
+        let mut utf8_encoded_doc = String::new();
+        decoding_reader
+            .read_to_string(&mut utf8_encoded_doc)
+            .expect("Failed decoding input data");
+        assert_eq!(utf8_encoded_doc, "<a>hello</a>");
+
+        let utf16le_bytes = include_bytes!("../tests/utf16le_bom/doc_xmldecl.xml");
+        let utf8_validation = include_bytes!("../tests/validation/utf16le_xmldecl.xml");
+        let mut decoding_reader =
+            with_capacity(&utf16le_bytes[..], 0).expect("Failed initializing read buffer");
+        let mut utf8_encoded_doc = String::new();
+        decoding_reader
+            .read_to_string(&mut utf8_encoded_doc)
+            .expect("Failed decoding input data");
+        assert_eq!(utf8_encoded_doc.as_bytes(), &utf8_validation[..]);
+    }
+
+    #[test]
+    fn test_position() {
+        let (shift_jis_bytes, _, _) = encoding_rs::SHIFT_JIS
+            .encode("<?xml version='1.0' encoding='shift_jis'?>\r\n<doc>\u{65e5}a</doc>");
+        let mut decoding_reader =
+            with_capacity(&shift_jis_bytes as &[u8], 4).expect("Failed initializing read buffer");
+        assert_eq!(
+            decoding_reader.position(),
+            Position {
+                offset: 0,
+                char_offset: 0,
+                line: 1,
+                column: 1
+            }
+        );
+        let mut decl = [0; 44];
+        decoding_reader
+            .read_exact(&mut decl)
+            .expect("Failed decoding input data");
+        assert_eq!(
+            decoding_reader.position(),
+            Position {
+                offset: 44,
+                char_offset: 44,
+                line: 2,
+                column: 1
+            }
+        );
+        // The first two of the three utf-8 bytes of U+65E5
+        let mut doc = [0; 7];
+        decoding_reader
+            .read_exact(&mut doc)
+            .expect("Failed decoding input data");
+        assert_eq!(
+            decoding_reader.position(),
+            Position {
+                offset: 49,
+                char_offset: 49,
+                line: 2,
+                column: 6
+            }
+        );
+        let mut rest: Vec<u8> = Vec::new();
+        decoding_reader
+            .read_to_end(&mut rest)
+            .expect("Failed decoding input data");
+        assert_eq!(
+            decoding_reader.position(),
+            Position {
+                offset: 58,
+                char_offset: 57,
+                line: 2,
+                column: 14
+            }
+        );
+    }
+
+    fn decode_short_doc(doc: &[u8]) -> String {
+        let mut decoding_reader = new(doc).expect("Failed initializing read buffer");
+        let mut utf8_encoded_doc: String = String::new();
+        decoding_reader
+            .read_to_string(&mut utf8_encoded_doc)
+            .expect("Failed decoding input data");
+        utf8_encoded_doc
+    }
+
+    #[test]
+    fn test_short_docs() {
+        assert_eq!(decode_short_doc(b""), "");
+        assert_eq!(decode_short_doc(b"a"), "a");
+        assert_eq!(decode_short_doc(b"<a>"), "<a>");
+        assert_eq!(decode_short_doc(b"<a/>"), "<a/>");
+        assert_eq!(decode_short_doc(b"<?xm"), "<?xm");
+        assert_eq!(decode_short_doc(b"<?xml"), "<?xml");
+        assert_eq!(decode_short_doc(b"\xEF\xBB\xBF"), "");
+        assert_eq!(decode_short_doc(b"\xEF\xBB\xBF<a/>"), "<a/>");
+        assert_eq!(decode_short_doc(b"\xFF\xFEa\x00"), "a");
+        assert_eq!(decode_short_doc(b"\xFF\xFE<\x00a\x00/\x00>\x00"), "<a/>");
+        assert_eq!(decode_short_doc(b"\xFE\xFF\x00<\x00a\x00/\x00>"), "<a/>");
+        assert_eq!(
+            decode_short_doc(b"\xFF\xFE\x00\x00<\x00\x00\x00/\x00\x00\x00"),
+            "</"
+        );
+    }
+
+    #[test]
+    fn test_short_doc_errors() {
+        // Looks like UTF-16 without a BOM
+        assert!(new(b"a\x00" as &[u8]).is_err());
+        // The xml declaration never ends
+        match new(b"<?xml version='1.0'" as &[u8]) {
+            Err(Error::MalformedXmlDecl(_)) => {}
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("Accepted an unterminated xml declaration"),
+        }
+    }
+
+    #[test]
+    fn test_xml_decl_accessors() {
+        let utf8_bytes =
+            b"<?xml version=\"1.1\" encoding=\"utf-8\" standalone=\"yes\"?>\n<doc/>\n".to_vec();
+        let decoding_reader = new(&utf8_bytes as &[u8]).expect("Failed initializing read buffer");
+        assert_eq!(decoding_reader.version(), Some("1.1"));
+        assert_eq!(decoding_reader.standalone(), Some(true));
+        let xml_decl = decoding_reader.xml_decl().expect("Missing xml declaration");
+        assert_eq!(xml_decl.encoding, Some("utf-8".to_string()));
+
+        let utf16le_bytes = include_bytes!("../tests/utf16le_bom/doc_xmldecl.xml").to_vec();
+        let decoding_reader =
+            new(&utf16le_bytes as &[u8]).expect("Failed initializing read buffer");
+        assert_eq!(decoding_reader.version(), Some("1.0"));
+        assert_eq!(decoding_reader.standalone(), None);
+        assert_eq!(
+            decoding_reader.xml_decl().map(|d| d.encoding.clone()),
+            Some(None)
+        );
+
+        let utf8_bytes = include_bytes!("../tests/utf8/doc.xml").to_vec();
+        let decoding_reader = new(&utf8_bytes as &[u8]).expect("Failed initializing read buffer");
+        assert!(decoding_reader.xml_decl().is_none());
+        assert_eq!(decoding_reader.version(), None);
+        assert_eq!(decoding_reader.standalone(), None);
+    }
+
+    fn decode_with_xml_decl_handling(doc: &[u8], handling: XmlDeclHandling) -> String {
+        let mut decoding_reader = new(doc).expect("Failed initializing read buffer");
+        decoding_reader.set_xml_decl_handling(handling);
+        let mut utf8_encoded_doc: String = String::new();
+        decoding_reader
+            .read_to_string(&mut utf8_encoded_doc)
+            .expect("Failed decoding input data");
+        utf8_encoded_doc
+    }
+
+    #[test]
+    fn test_xml_decl_handling() {
+        let utf16le_bytes =
+            include_bytes!("../tests/utf16le_bom/doc_xmldecl_encodingdecl.xml").to_vec();
+        let utf16le_validation =
+            include_str!("../tests/validation/utf16le_xmldecl_encodingdecl.xml");
+        let content = &utf16le_validation[utf16le_validation.find("?>").unwrap() + 2..];
+
+        assert_eq!(
+            decode_with_xml_decl_handling(&utf16le_bytes, XmlDeclHandling::Keep),
+            utf16le_validation
+        );
+        assert_eq!(
+            decode_with_xml_decl_handling(&utf16le_bytes, XmlDeclHandling::Strip),
+            content
+        );
+        assert_eq!(
+            decode_with_xml_decl_handling(&utf16le_bytes, XmlDeclHandling::RewriteEncoding),
+            format!("<?xml version=\"1.0\" encoding=\"utf-8\"?>{}", content)
+        );
+
+        // Without a declaration there's nothing to strip or rewrite
+        let utf8_bytes = include_bytes!("../tests/utf8/doc.xml").to_vec();
+        let utf8_validation = include_str!("../tests/validation/utf8.xml");
+        assert_eq!(
+            decode_with_xml_decl_handling(&utf8_bytes, XmlDeclHandling::Strip),
+            utf8_validation
+        );
+        assert_eq!(
+            decode_with_xml_decl_handling(&utf8_bytes, XmlDeclHandling::RewriteEncoding),
+            utf8_validation
+        );
+    }
+
+    #[test]
+    fn test_xml_decl_handling_after_read() {
+        let utf8_bytes = b"<?xml version='1.0'?><doc/>".to_vec();
+        let mut decoding_reader =
+            new(&utf8_bytes as &[u8]).expect("Failed initializing read buffer");
+        let mut first = [0; 1];
+        decoding_reader
+            .read_exact(&mut first)
+            .expect("Failed decoding input data");
+        decoding_reader.set_xml_decl_handling(XmlDeclHandling::Strip);
+        let mut rest: String = String::new();
+        decoding_reader
+            .read_to_string(&mut rest)
+            .expect("Failed decoding input data");
+        assert_eq!(rest, "?xml version='1.0'?><doc/>");
+    }
+
+    #[test]
+    fn test_rewritten_xml_decl_position() {
+        let doc = b"<?xml version='1.0' encoding='us-ascii'?><doc/>".to_vec();
+        let mut decoding_reader = new(&doc as &[u8]).expect("Failed initializing read buffer");
+        decoding_reader.set_xml_decl_handling(XmlDeclHandling::RewriteEncoding);
+        let (decoded, positions) = read_positions(&mut decoding_reader);
+        let decl_len = decoded.find("<doc/>").unwrap();
+        // Each char of the rewritten declaration comes from where the original one starts
+        for position in &positions[..decl_len] {
+            assert_eq!(position.offset, 0);
+        }
+        assert_eq!(positions[decl_len].offset, doc.len() - "<doc/>".len());
+        assert_eq!(positions[positions.len() - 1].offset, doc.len());
+    }
+
+    fn decode_with_eol_normalization(doc: &[u8], capacity: usize) -> String {
+        let mut decoding_reader =
+            with_capacity(doc, capacity).expect("Failed initializing read buffer");
+        decoding_reader.set_eol_normalization(true);
+        let mut utf8_encoded_doc: String = String::new();
+        decoding_reader
+            .read_to_string(&mut utf8_encoded_doc)
+            .expect("Failed decoding input data");
+        utf8_encoded_doc
+    }
+
+    #[test]
+    fn test_eol_normalization() {
+        let xml10_doc = "<?xml version='1.0'?>\r\n<doc>a\rb\r\nc\u{85}d\u{2028}</doc>\r\n";
+        let xml11_doc = "<?xml version='1.1'?>\r\n<doc>a\rb\r\u{85}c\u{85}d\u{2028}</doc>\r\n";
+        // A capacity of one splits every "\r\n" pair across refills
+        for capacity in &[1, DEFAULT_BUF_SIZE] {
+            assert_eq!(
+                decode_with_eol_normalization(xml10_doc.as_bytes(), *capacity),
+                "<?xml version='1.0'?>\n<doc>a\nb\nc\u{85}d\u{2028}</doc>\n"
+            );
+            assert_eq!(
+                decode_with_eol_normalization(xml11_doc.as_bytes(), *capacity),
+                "<?xml version='1.1'?>\n<doc>a\nb\nc\nd\n</doc>\n"
+            );
+        }
+
+        // UTF-16 pairs can be split mid-character as well as between characters
+        let mut utf16le_bytes = vec![0xFF, 0xFE];
+        for c in "<doc>\r\n</doc>\r".encode_utf16() {
+            utf16le_bytes.push(c as u8);
+            utf16le_bytes.push((c >> 8) as u8);
+        }
+        for capacity in &[1, 2, 3, DEFAULT_BUF_SIZE] {
+            assert_eq!(
+                decode_with_eol_normalization(&utf16le_bytes, *capacity),
+                "<doc>\n</doc>\n"
+            );
+        }
+
+        // Normalization is off by default
+        let utf8_bytes = b"<doc>\r\n</doc>".to_vec();
+        let mut decoding_reader =
+            new(&utf8_bytes as &[u8]).expect("Failed initializing read buffer");
+        let mut utf8_encoded_doc: String = String::new();
+        decoding_reader
+            .read_to_string(&mut utf8_encoded_doc)
+            .expect("Failed decoding input data");
+        assert_eq!(utf8_encoded_doc, "<doc>\r\n</doc>");
+    }
+
+    fn illegal_char_error(doc: &[u8], capacity: usize) -> (char, Position) {
+        let mut decoding_reader =
+            with_capacity(doc, capacity).expect("Failed initializing read buffer");
+        decoding_reader.set_char_validation(true);
+        let mut utf8_encoded_doc: String = String::new();
+        let e = decoding_reader
+            .read_to_string(&mut utf8_encoded_doc)
+            .expect_err("Decoded an illegal char");
+        match e.into_inner().map(|e| e.downcast::<Error>()) {
+            Some(Ok(e)) => match *e {
+                Error::IllegalChar { c, position } => (c, position),
+                e => panic!("Unexpected error: {}", e),
+            },
+            _ => panic!("Unexpected error"),
+        }
+    }
+
+    #[test]
+    fn test_char_validation() {
+        let utf8_doc = "<doc>\r\n\u{e9}\u{0}</doc>";
+        for capacity in &[1, DEFAULT_BUF_SIZE] {
+            assert_eq!(
+                illegal_char_error(utf8_doc.as_bytes(), *capacity),
+                (
+                    '\u{0}',
+                    Position {
+                        offset: 9,
+                        char_offset: 8,
+                        line: 2,
+                        column: 2
+                    }
+                )
+            );
+        }
+
+        let mut utf16be_bytes = vec![0xFE, 0xFF];
+        for c in "<doc>\n\u{10000}\u{FFFF}</doc>".encode_utf16() {
+            utf16be_bytes.push((c >> 8) as u8);
+            utf16be_bytes.push(c as u8);
+        }
+        assert_eq!(
+            illegal_char_error(&utf16be_bytes, DEFAULT_BUF_SIZE),
+            (
+                '\u{FFFF}',
+                Position {
+                    offset: 18,
+                    char_offset: 7,
+                    line: 2,
+                    column: 2
+                }
+            )
+        );
+
+        // Shift_JIS chars are one or two bytes long
+        let (shift_jis_bytes, _, _) = encoding_rs::SHIFT_JIS.encode(
+            "<?xml version='1.0' encoding='shift_jis'?>\n<doc>\u{65e5}a\u{672c}\u{1}</doc>",
+        );
+        for capacity in &[1, 3, DEFAULT_BUF_SIZE] {
+            assert_eq!(
+                illegal_char_error(&shift_jis_bytes, *capacity),
+                (
+                    '\u{1}',
+                    Position {
+                        offset: 53,
+                        char_offset: 51,
+                        line: 2,
+                        column: 9
+                    }
+                )
+            );
+        }
+
+        // C1 controls are only illegal in xml 1.1
+        let xml10_doc = "<?xml version='1.0'?><doc>\u{80}</doc>";
+        let mut decoding_reader =
+            new(xml10_doc.as_bytes()).expect("Failed initializing read buffer");
+        decoding_reader.set_char_validation(true);
+        let mut utf8_encoded_doc: String = String::new();
+        decoding_reader
+            .read_to_string(&mut utf8_encoded_doc)
+            .expect("Failed decoding input data");
+        assert_eq!(utf8_encoded_doc, xml10_doc);
+
+        let xml11_doc = "<?xml version='1.1'?><doc>\u{80}</doc>";
+        assert_eq!(
+            illegal_char_error(xml11_doc.as_bytes(), DEFAULT_BUF_SIZE),
+            (
+                '\u{80}',
+                Position {
+                    offset: 26,
+                    char_offset: 26,
+                    line: 1,
+                    column: 27
+                }
+            )
+        );
+    }
+
+    #[test]
+    fn test_source_range() {
+        let mut utf16le_bytes = vec![0xFF, 0xFE];
+        for c in "<doc>\u{10000}\u{e9}</doc>".encode_utf16() {
+            utf16le_bytes.extend_from_slice(&c.to_le_bytes());
+        }
+        let (shift_jis_bytes, _, _) = encoding_rs::SHIFT_JIS
+            .encode("<?xml version='1.0' encoding='shift_jis'?><doc>\u{65e5}a</doc>");
+        for capacity in &[1, DEFAULT_BUF_SIZE] {
+            let mut decoding_reader = with_capacity(&utf16le_bytes as &[u8], *capacity)
+                .expect("Failed initializing read buffer");
+            decoding_reader.set_offset_map(Some(1));
+            assert_eq!(decoding_reader.source_range(0..1), None);
+            let mut utf8_encoded_doc: String = String::new();
+            decoding_reader
+                .read_to_string(&mut utf8_encoded_doc)
+                .expect("Failed decoding input data");
+            let start = utf8_encoded_doc.find('\u{10000}').unwrap();
+            assert_eq!(decoding_reader.source_range(start..start + 4), Some(12..16));
+            assert_eq!(
+                decoding_reader.source_range(start + 4..start + 6),
+                Some(16..18)
+            );
+            assert_eq!(decoding_reader.source_range(0..1), Some(2..4));
+            assert_eq!(
+                decoding_reader.source_range(0..utf8_encoded_doc.len()),
+                Some(2..utf16le_bytes.len())
+            );
+            assert_eq!(
+                decoding_reader.source_range(0..utf8_encoded_doc.len() + 1),
+                None
+            );
+
+            let mut decoding_reader = with_capacity(&shift_jis_bytes as &[u8], *capacity)
+                .expect("Failed initializing read buffer");
+            decoding_reader.set_offset_map(Some(4));
+            let mut utf8_encoded_doc: String = String::new();
+            decoding_reader
+                .read_to_string(&mut utf8_encoded_doc)
+                .expect("Failed decoding input data");
+            let start = utf8_encoded_doc.find('\u{65e5}').unwrap();
+            // The checkpoints are at chars 44 and 48, on either side of U+65E5
+            assert_eq!(decoding_reader.source_range(start..start + 3), Some(44..49));
+        }
+
+        // Without a map there's nothing to look up
+        let decoding_reader =
+            new(&utf16le_bytes as &[u8]).expect("Failed initializing read buffer");
+        assert_eq!(decoding_reader.source_range(0..0), None);
+    }
+
+    fn decode_leniently(
+        doc: &[u8],
+        capacity: usize,
+        handling: MalformedInputHandling,
+    ) -> (String, Vec<usize>) {
+        let mut decoding_reader =
+            with_capacity(doc, capacity).expect("Failed initializing read buffer");
+        decoding_reader.set_malformed_input_handling(handling);
+        let mut utf8_encoded_doc: String = String::new();
+        decoding_reader
+            .read_to_string(&mut utf8_encoded_doc)
+            .expect("Failed decoding input data");
+        (utf8_encoded_doc, decoding_reader.substitutions().to_vec())
+    }
+
+    #[test]
+    fn test_malformed_input_handling() {
+        let utf8_bytes = b"<doc>\xFFa\xE2\x82</doc>\xC3".to_vec();
+        for capacity in &[1, 4, DEFAULT_BUF_SIZE] {
+            assert_eq!(
+                decode_leniently(&utf8_bytes, *capacity, MalformedInputHandling::Replace),
+                (
+                    "<doc>\u{FFFD}a\u{FFFD}</doc>\u{FFFD}".to_string(),
+                    vec![5, 7, 15]
+                )
+            );
+            assert_eq!(
+                decode_leniently(&utf8_bytes, *capacity, MalformedInputHandling::Skip),
+                ("<doc>a</doc>".to_string(), vec![5, 7, 15])
+            );
+        }
+        match new(&utf8_bytes as &[u8]) {
+            Ok(mut decoding_reader) => {
+                let mut utf8_encoded_doc: String = String::new();
+                assert!(decoding_reader
+                    .read_to_string(&mut utf8_encoded_doc)
+                    .is_err());
+                assert!(decoding_reader.substitutions().is_empty());
+            }
+            Err(e) => panic!("Failed initializing read buffer: {}", e),
+        }
+
+        // A lone surrogate
+        let utf16le_bytes = b"\xFF\xFE<\x00\x00\xD8a\x00>\x00".to_vec();
+        assert_eq!(
+            decode_leniently(
+                &utf16le_bytes,
+                DEFAULT_BUF_SIZE,
+                MalformedInputHandling::Replace
+            ),
+            ("<\u{FFFD}a>".to_string(), vec![4])
+        );
+
+        // A UTF-32 code unit out of range, and a trailing partial one
+        let utf32be_bytes = b"\x00\x00\xFE\xFF\x00\x11\x00\x00\x00\x00\x00a\x00\x00".to_vec();
+        assert_eq!(
+            decode_leniently(
+                &utf32be_bytes,
+                DEFAULT_BUF_SIZE,
+                MalformedInputHandling::Replace
+            ),
+            ("\u{FFFD}a\u{FFFD}".to_string(), vec![4, 12])
+        );
+    }
+
+    #[test]
+    fn test_malformed_input_handling_with_char_validation() {
+        // Substitutions still line up with the source when char offsets are being tracked
+        let (shift_jis_bytes, _, _) = encoding_rs::SHIFT_JIS
+            .encode("<?xml version='1.0' encoding='shift_jis'?><doc>\u{65e5}\u{672c}</doc>");
+        let mut shift_jis_bytes = shift_jis_bytes.into_owned();
+        // A lead byte followed by a byte that can't trail it
+        shift_jis_bytes.insert(49, 0x81);
+        shift_jis_bytes.insert(50, 0x20);
+        shift_jis_bytes.extend(b"\x00");
+        for capacity in &[1, DEFAULT_BUF_SIZE] {
+            let mut decoding_reader = with_capacity(&shift_jis_bytes as &[u8], *capacity)
+                .expect("Failed initializing read buffer");
+            decoding_reader.set_malformed_input_handling(MalformedInputHandling::Replace);
+            decoding_reader.set_char_validation(true);
+            let mut utf8_encoded_doc: String = String::new();
+            let e = decoding_reader
+                .read_to_string(&mut utf8_encoded_doc)
+                .expect_err("Decoded an illegal char");
+            assert_eq!(decoding_reader.substitutions(), &[49]);
+            match e.get_ref().and_then(|e| e.downcast_ref::<Error>()) {
+                Some(Error::IllegalChar { c, position }) => {
+                    assert_eq!(*c, '\u{0}');
+                    assert_eq!(position.offset, 59);
+                    assert_eq!(position.column, 58);
+                }
+                _ => panic!("Unexpected error: {}", e),
+            }
+        }
+    }
+
+    // Reads the document a byte at a time, and notes the position after each byte
+    fn read_positions(decoding_reader: &mut DecodingReader<&[u8]>) -> (String, Vec<Position>) {
+        let mut utf8_encoded_doc = Vec::new();
+        let mut positions = vec![decoding_reader.position()];
+        let mut byte = [0; 1];
+        while decoding_reader
+            .read(&mut byte)
+            .expect("Failed decoding input data")
+            > 0
+        {
+            utf8_encoded_doc.push(byte[0]);
+            positions.push(decoding_reader.position());
+        }
+        (
+            String::from_utf8(utf8_encoded_doc).expect("Decoded invalid utf-8"),
+            positions,
+        )
+    }
+
+    // Where each char of bytes starts, found by decoding them a byte at a time, and then where
+    // they end
+    fn char_starts(encoding: &'static encoding_rs::Encoding, bytes: &[u8]) -> Vec<usize> {
+        let mut decoder = encoding.new_decoder_without_bom_handling();
+        let mut starts = Vec::new();
+        let mut start = 0;
+        for i in 0..bytes.len() {
+            let mut decoded = String::with_capacity(16);
+            let (result, _) = decoder.decode_to_string_without_replacement(
+                &bytes[i..i + 1],
+                &mut decoded,
+                i + 1 == bytes.len(),
+            );
+            assert_eq!(result, encoding_rs::DecoderResult::InputEmpty);
+            for _ in decoded.chars() {
+                starts.push(start);
+            }
+            if !decoded.is_empty() {
+                start = i + 1;
+            }
+        }
+        starts.push(bytes.len());
+        starts
+    }
+
+    #[test]
+    fn test_position_multi_byte_encodings() {
+        let cases: [(&'static encoding_rs::Encoding, &[u8]); 7] = [
+            (encoding_rs::SHIFT_JIS, b""),
+            // A JIS X 0212 char, which the encoder never produces
+            (encoding_rs::EUC_JP, b"\x8F\xB0\xA1"),
+            (encoding_rs::EUC_KR, b""),
+            // A letter with a combining mark
+            (encoding_rs::BIG5, b"\x88\x62"),
+            (encoding_rs::GBK, b""),
+            (encoding_rs::GB18030, b""),
+            (encoding_rs::ISO_2022_JP, b"\x1B$B\x30\x21\x1B(B"),
+        ];
+        for &(encoding, extra) in cases.iter() {
+            let head = format!(
+                "<?xml version='1.0' encoding='{}'?>\r\n<doc>a\u{65e5}\u{ff71}b\u{20ac}",
+                encoding.name()
+            );
+            let (head, _, _) = encoding.encode(&head);
+            let (tail, _, _) = encoding.encode("\u{80}\u{672c}\u{10000}\u{3042}c</doc>\r\n");
+            let mut doc = head.into_owned();
+            doc.extend_from_slice(extra);
+            doc.extend_from_slice(&tail);
+            let starts = char_starts(encoding, &doc);
+
+            for &capacity in [1, 3, DEFAULT_BUF_SIZE].iter() {
+                let mut decoding_reader = with_capacity(&doc as &[u8], capacity)
+                    .expect("Failed initializing read buffer");
+                let (decoded, positions) = read_positions(&mut decoding_reader);
+                for (bytes_read, position) in positions.iter().enumerate() {
+                    let chars_read = decoded
+                        .char_indices()
+                        .take_while(|&(i, c)| i + c.len_utf8() <= bytes_read)
+                        .count();
+                    assert_eq!(
+                        position.offset,
+                        starts[chars_read],
+                        "{} with capacity {}, after {} bytes",
+                        encoding.name(),
+                        capacity,
+                        bytes_read
+                    );
+                    assert_eq!(position.char_offset, chars_read);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_utf8_passthrough() {
+        let utf8_bytes = "<?xml version='1.0'?>\r\n<doc>\u{65e5}\u{e9}\r\u{1f600}a</doc>\n";
+        for capacity in &[1, 2, 3, 5, DEFAULT_BUF_SIZE] {
+            let mut builder = ReaderBuilder::new();
+            builder.capacity(*capacity);
+            let mut decoding_reader = builder
+                .build(utf8_bytes.as_bytes())
+                .expect("Failed initializing read buffer");
+            let passed_through = read_positions(&mut decoding_reader);
+            let mut decoding_reader = builder
+                .utf8_passthrough(false)
+                .build(utf8_bytes.as_bytes())
+                .expect("Failed initializing read buffer");
+            let decoded = read_positions(&mut decoding_reader);
+            assert_eq!(passed_through.0, utf8_bytes);
+            assert_eq!(passed_through, decoded);
+        }
+    }
+
+    #[test]
+    fn test_utf8_passthrough_malformed_input() {
+        let utf8_bytes = b"<doc>\xe6\x97\xa5\xe6\x97<a/>\xff\xe6\x97\xa5</doc>";
+        for capacity in &[1, 4, DEFAULT_BUF_SIZE] {
+            let mut decoding_reader =
+                with_capacity(&utf8_bytes[..], *capacity).expect("Failed initializing read buffer");
+            decoding_reader.set_malformed_input_handling(MalformedInputHandling::Replace);
+            let mut utf8_encoded_doc: String = String::new();
+            decoding_reader
+                .read_to_string(&mut utf8_encoded_doc)
+                .expect("Failed decoding input data");
+            assert_eq!(
+                utf8_encoded_doc,
+                "<doc>\u{65e5}\u{fffd}<a/>\u{fffd}\u{65e5}</doc>"
+            );
+            assert_eq!(decoding_reader.substitutions(), &[8, 14]);
+
+            let mut decoding_reader =
+                with_capacity(&utf8_bytes[..], *capacity).expect("Failed initializing read buffer");
+            let mut utf8_encoded_doc: String = String::new();
+            let e = decoding_reader
+                .read_to_string(&mut utf8_encoded_doc)
+                .expect_err("Decoded malformed input");
+            match e.get_ref().and_then(|e| e.downcast_ref::<Error>()) {
+                Some(Error::MalformedInput { position }) => assert_eq!(position.offset, 8),
+                _ => panic!("Unexpected error: {}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn test_fill_str() {
+        let utf8_validation = include_bytes!("../tests/validation/utf8_xmldecl.xml");
+        let utf8_bytes = include_bytes!("../tests/utf8_bom/doc_xmldecl.xml");
+        let utf16_bytes = include_bytes!("../tests/utf16le_bom/doc_xmldecl.xml");
+        for doc in &[&utf8_bytes[..], &utf16_bytes[..]] {
+            for buf_capacity in &[1, 5, DEFAULT_BUF_SIZE] {
+                let mut decoding_reader =
+                    with_capacity(io::BufReader::with_capacity(*buf_capacity, *doc), 3)
+                        .expect("Failed initializing read buffer");
+                let mut utf8_encoded_doc = String::new();
+                loop {
+                    let text_len = {
+                        let text = decoding_reader
+                            .fill_str()
+                            .expect("Failed decoding input data");
+                        utf8_encoded_doc.push_str(text);
+                        text.len()
+                    };
+                    if text_len == 0 {
+                        break;
+                    }
+                    decoding_reader.consume_str(text_len);
+                }
+                assert_eq!(utf8_encoded_doc.as_bytes(), &utf8_validation[..]);
+                let position = decoding_reader.position();
+                assert_eq!(position.offset, doc.len());
+                assert_eq!(position.char_offset, utf8_encoded_doc.chars().count());
+            }
+        }
+
+        // Text is borrowed from the wrapped reader once what detection read has been decoded
+        let utf8_bytes = "<a>\u{65e5}\r\nb</a>";
+        let mut decoding_reader = with_capacity(io::BufReader::new(utf8_bytes.as_bytes()), 1)
+            .expect("Failed initializing read buffer");
+        assert_eq!(
+            decoding_reader
+                .fill_str()
+                .expect("Failed decoding input data"),
+            "<a>\u{65e5}\r"
+        );
+        decoding_reader.consume_str(7);
+        let text_ptr = {
+            let text = decoding_reader
+                .fill_str()
+                .expect("Failed decoding input data");
+            assert_eq!(text, "\nb</a>");
+            text.as_ptr()
+        };
+        assert_eq!(text_ptr, decoding_reader.get_ref().buffer().as_ptr());
+        decoding_reader.consume_str(1);
+        assert_eq!(
+            decoding_reader.position(),
+            Position {
+                offset: 8,
+                char_offset: 6,
+                line: 2,
+                column: 1
+            }
+        );
+        assert_eq!(
+            decoding_reader
+                .fill_str()
+                .expect("Failed decoding input data"),
+            "b</a>"
+        );
+
+        // A read that ends partway through a char leaves all of it to fill_str
+        let utf8_bytes = "<a>\u{65e5}</a>";
+        let mut decoding_reader = new(io::BufReader::new(utf8_bytes.as_bytes()))
+            .expect("Failed initializing read buffer");
+        let mut start = [0; 5];
+        decoding_reader
+            .read_exact(&mut start)
+            .expect("Failed decoding input data");
+        let text_len = {
+            let text = decoding_reader
+                .fill_str()
+                .expect("Failed decoding input data");
+            assert_eq!(text, "\u{65e5}</a>");
+            text.len()
+        };
+        assert_eq!(decoding_reader.position().offset, 3);
+        decoding_reader.consume_str(text_len);
+        assert_eq!(decoding_reader.position().offset, utf8_bytes.len());
+        assert_eq!(
+            decoding_reader
+                .fill_str()
+                .expect("Failed decoding input data"),
+            ""
+        );
+    }
+
+    #[test]
+    fn test_buf_read() {
+        let utf8_validation = include_bytes!("../tests/validation/utf16le_xmldecl.xml");
+        let utf16_bytes = include_bytes!("../tests/utf16le_bom/doc_xmldecl.xml");
+        for capacity in &[1, 3, DEFAULT_BUF_SIZE] {
+            let decoding_reader = with_capacity(&utf16_bytes[..], *capacity)
+                .expect("Failed initializing read buffer");
+            let lines: Vec<String> = decoding_reader
+                .lines()
+                .collect::<io::Result<_>>()
+                .expect("Failed decoding input data");
+            let validation_lines: Vec<&str> = std::str::from_utf8(utf8_validation)
+                .expect("Invalid validation doc")
+                .lines()
+                .collect();
+            assert_eq!(lines, validation_lines);
+
+            let mut decoding_reader = with_capacity(&utf16_bytes[..], *capacity)
+                .expect("Failed initializing read buffer");
+            let mut utf8_encoded_doc = Vec::new();
+            while decoding_reader
+                .read_until(b'>', &mut utf8_encoded_doc)
+                .expect("Failed decoding input data")
+                > 0
+            {
+                assert!(
+                    utf8_encoded_doc.ends_with(b">")
+                        || utf8_encoded_doc.len() == utf8_validation.len()
+                );
+            }
+            assert_eq!(&utf8_encoded_doc[..], &utf8_validation[..]);
+        }
+    }
+
+    #[test]
+    fn test_buf_read_position() {
+        let (shift_jis_bytes, _, _) = encoding_rs::SHIFT_JIS
+            .encode("<?xml version='1.0' encoding='shift_jis'?>\r\n<doc>\u{65e5}a</doc>");
+        let mut decoding_reader =
+            with_capacity(&shift_jis_bytes as &[u8], 4).expect("Failed initializing read buffer");
+        let mut line = String::new();
+        decoding_reader
+            .read_line(&mut line)
+            .expect("Failed decoding input data");
+        assert_eq!(line, "<?xml version='1.0' encoding='shift_jis'?>\r\n");
+        assert_eq!(
+            decoding_reader.position(),
+            Position {
+                offset: 44,
+                char_offset: 44,
+                line: 2,
+                column: 1
+            }
+        );
+        let mut start_tag = Vec::new();
+        decoding_reader
+            .read_until(b'>', &mut start_tag)
+            .expect("Failed decoding input data");
+        assert_eq!(start_tag, b"<doc>");
+        assert_eq!(
+            decoding_reader.position(),
+            Position {
+                offset: 49,
+                char_offset: 49,
+                line: 2,
+                column: 6
+            }
+        );
+        // A char that's only been partly consumed hasn't been read yet
+        assert_eq!(
+            decoding_reader
+                .fill_buf()
+                .expect("Failed decoding input data")[0],
+            0xe6
+        );
+        decoding_reader.consume(1);
+        assert_eq!(decoding_reader.position().offset, 49);
+        let mut rest = String::new();
+        decoding_reader
+            .read_to_string(&mut rest)
+            .expect_err("Reading the rest of a partly consumed char should fail");
+    }
 }
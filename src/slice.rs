@@ -0,0 +1,200 @@
+use enc_detect::DetectionReport;
+use error::Result;
+use reader::ReaderBuilder;
+
+use std::borrow::Cow;
+
+/// Detects the input encoding of a document that's all in memory, with the default options of
+/// `ReaderBuilder`.
+pub fn detect(input: &[u8]) -> Result<DetectionReport> {
+    ReaderBuilder::new().detect_slice(input)
+}
+
+/// Decodes a document that's all in memory to utf-8, with the default options of
+/// `ReaderBuilder`. A document in utf-8 is passed through: the result borrows `input`, less any
+/// byte order mark, without copying it. A document in any other encoding is decoded into a newly
+/// allocated `String`. Malformed input fails with `Error::MalformedInput` either way.
+pub fn decode<'a>(input: &'a [u8]) -> Result<Cow<'a, str>> {
+    ReaderBuilder::new().decode_slice(input)
+}
+
+impl ReaderBuilder {
+    /// Detects the input encoding of a document that's all in memory, e.g. a memory-mapped file,
+    /// the same way `build` does for a reader.
+    pub fn detect_slice(&self, input: &[u8]) -> Result<DetectionReport> {
+        let (_, _, detection) = self.detect(&mut &input[..])?;
+        Ok(detection)
+    }
+
+    /// Decodes a document that's all in memory to utf-8. When the document is valid utf-8 that
+    /// decoding would leave as it is, the result borrows it, less any byte order mark.
+    ///
+    /// ```
+    /// use std::borrow::Cow;
+    ///
+    /// let decoded = xmlbufrw::slice::decode(b"<?xml version='1.0'?><a/>")?;
+    /// assert!(match decoded {
+    ///     Cow::Borrowed(decoded) => decoded == "<?xml version='1.0'?><a/>",
+    ///     Cow::Owned(_) => false,
+    /// });
+    /// # Ok::<(), xmlbufrw::Error>(())
+    /// ```
+    pub fn decode_slice<'a>(&self, input: &'a [u8]) -> Result<Cow<'a, str>> {
+        let mut rest = input;
+        let (encoding, prebuf, detection) = self.detect(&mut rest)?;
+        let mut decoding_reader = self.build_detected(rest, encoding, prebuf, detection)?;
+        decoding_reader.check_size_limit_at(input.len())?;
+        if let Some(offset) = decoding_reader.passthrough_offset() {
+            if let Ok(decoded) = std::str::from_utf8(&input[offset..]) {
+                return Ok(Cow::Borrowed(decoded));
+            }
+        }
+
+        let mut decoded = String::with_capacity(input.len());
+        loop {
+            decoding_reader.fill_output_buf()?;
+            let decoded_len = decoded.len();
+            decoding_reader.take_output(&mut decoded);
+            if decoded.len() == decoded_len {
+                return Ok(Cow::Owned(decoded));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod slice_tests {
+    use super::*;
+    use error::Error;
+    use reader::{MalformedInputHandling, XmlDeclHandling};
+    use std::io::Read;
+
+    #[test]
+    fn test_decode() {
+        for &(doc, utf8_validation, borrowed) in &[
+            (
+                include_bytes!("../tests/utf8/doc_xmldecl_encodingdecl.xml") as &[u8],
+                include_bytes!("../tests/validation/utf8_xmldecl_encodingdecl.xml") as &[u8],
+                true,
+            ),
+            (
+                include_bytes!("../tests/utf8_bom/doc.xml") as &[u8],
+                include_bytes!("../tests/validation/utf8.xml") as &[u8],
+                true,
+            ),
+            (
+                include_bytes!("../tests/utf16be_bom/doc_xmldecl.xml") as &[u8],
+                include_bytes!("../tests/validation/utf16be_xmldecl.xml") as &[u8],
+                false,
+            ),
+            (
+                include_bytes!("../tests/utf32le/doc_xmldecl_encodingdecl.xml") as &[u8],
+                include_bytes!("../tests/validation/utf32le_xmldecl_encodingdecl.xml") as &[u8],
+                false,
+            ),
+            (
+                include_bytes!("../tests/ibm037/doc_xmldecl_encodingdecl.xml") as &[u8],
+                include_bytes!("../tests/validation/ibm037_xmldecl_encodingdecl.xml") as &[u8],
+                false,
+            ),
+            (
+                include_bytes!("../tests/euc-kr/doc_xmldecl_encodingdecl.xml") as &[u8],
+                include_bytes!("../tests/validation/euc-kr_xmldecl_encodingdecl.xml") as &[u8],
+                false,
+            ),
+        ] {
+            let decoded = decode(doc).expect("Failed decoding input data");
+            assert_eq!(decoded.as_bytes(), utf8_validation);
+            assert_eq!(matches!(decoded, Cow::Borrowed(_)), borrowed);
+        }
+    }
+
+    #[test]
+    fn test_decode_options() {
+        let utf8_bytes = b"<?xml version='1.0'?>\r\n<a>\xff</a>";
+        // Decoding changes the document, so it's copied
+        let mut builder = ReaderBuilder::new();
+        builder.malformed_input_handling(MalformedInputHandling::Replace);
+        let decoded = builder
+            .decode_slice(utf8_bytes)
+            .expect("Failed decoding input data");
+        assert_eq!(decoded, "<?xml version='1.0'?>\r\n<a>\u{fffd}</a>");
+        assert!(matches!(decoded, Cow::Owned(_)));
+
+        let utf8_bytes = b"<?xml version='1.0'?>\r\n<a/>";
+        let decoded = builder
+            .xml_decl_handling(XmlDeclHandling::Strip)
+            .decode_slice(utf8_bytes)
+            .expect("Failed decoding input data");
+        assert_eq!(decoded, "\r\n<a/>");
+        assert!(matches!(decoded, Cow::Borrowed(_)));
+        let decoded = builder
+            .eol_normalization(true)
+            .decode_slice(utf8_bytes)
+            .expect("Failed decoding input data");
+        assert_eq!(decoded, "\n<a/>");
+        assert!(matches!(decoded, Cow::Owned(_)));
+
+        // The same as reading the document
+        let mut utf8_encoded_doc = String::new();
+        builder
+            .xml_decl_handling(XmlDeclHandling::RewriteEncoding)
+            .build(&utf8_bytes[..])
+            .expect("Failed initializing read buffer")
+            .read_to_string(&mut utf8_encoded_doc)
+            .expect("Failed decoding input data");
+        assert_eq!(
+            builder
+                .decode_slice(utf8_bytes)
+                .expect("Failed decoding input data"),
+            utf8_encoded_doc
+        );
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        match decode(b"<a>\xe6\x97</a>") {
+            Err(Error::MalformedInput { position }) => assert_eq!(position.offset, 3),
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("Decoded malformed input"),
+        }
+        match decode(include_bytes!("../tests/utf16le/doc.xml")) {
+            Err(Error::NoBomNoDecl) => {}
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("Utf-16 without a BOM or xml declaration should fail detection"),
+        }
+        match ReaderBuilder::new()
+            .size_limit(Some(8))
+            .decode_slice(b"<a>b</a>\n")
+        {
+            Err(Error::SizeLimitExceeded { limit }) => assert_eq!(limit, 8),
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("Decoded past the size limit"),
+        }
+    }
+
+    #[test]
+    fn test_detect() {
+        let doc = include_bytes!("../tests/shift_jis/doc_xmldecl_encodingdecl.xml");
+        let detection = detect(doc).expect("Failed detecting input encoding");
+        assert_eq!(detection.encoding, "shift_jis".to_string());
+        assert_eq!(
+            &detection,
+            ReaderBuilder::new()
+                .build(&doc[..])
+                .expect("Failed initializing read buffer")
+                .detection_report()
+        );
+
+        let detection = ReaderBuilder::new()
+            .content_type("text/xml; charset=koi8-r")
+            .detect_slice(b"<a/>")
+            .expect("Failed detecting input encoding");
+        assert_eq!(detection.encoding, "koi8-r".to_string());
+        match detect(b"<?xml version='1.0' encoding='no-such-encoding'?>") {
+            Err(Error::UnsupportedEncoding(_)) => {}
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("An unknown encoding declaration should fail detection"),
+        }
+    }
+}
@@ -0,0 +1,996 @@
+use ebcdic;
+use enc_detect::Encoding;
+use error::{Error, Result};
+use xml_decl::XmlDecl;
+
+use DEFAULT_BUF_SIZE;
+
+use encoding_rs;
+
+use std::io;
+use std::io::Write;
+
+pub fn new<W: Write>(inner: W) -> Result<EncodingWriter<W>> {
+    with_capacity_and_output_encoding(inner, DEFAULT_BUF_SIZE, None)
+}
+
+pub fn with_capacity<W: Write>(inner: W, capacity: usize) -> Result<EncodingWriter<W>> {
+    with_capacity_and_output_encoding(inner, capacity, None)
+}
+
+pub fn with_capacity_and_output_encoding<W: Write>(
+    inner: W,
+    capacity: usize,
+    output_encoding: Option<String>,
+) -> Result<EncodingWriter<W>> {
+    let encoding_name = output_encoding.unwrap_or_else(|| "utf-8".to_string());
+    let encoding = Encoding::new_from_name(&encoding_name, true)?;
+    let target = OutputTarget::new(&encoding);
+
+    Ok(EncodingWriter {
+        inner: Some(inner),
+        encoding,
+        target,
+        prolog: Prolog::default(),
+        markup: MarkupContext::new(),
+        pending: Vec::with_capacity(4),
+        output_buf: Vec::with_capacity(capacity),
+        capacity,
+    })
+}
+
+// encoding_rs only encodes into ASCII-compatible encodings (its UTF-16 encodings report utf-8
+// as their output encoding, and it has no UTF-32 or EBCDIC), so everything else is serialized
+// by hand.
+enum OutputTarget {
+    Encoder(encoding_rs::Encoder),
+    Utf16Le,
+    Utf16Be,
+    Utf32Le,
+    Utf32Be,
+    Ebcdic(&'static ebcdic::CodePage, Box<[u8; 256]>),
+}
+
+impl OutputTarget {
+    fn new(encoding: &Encoding) -> Self {
+        match encoding {
+            Encoding::Whatwg(rs_encoding, _) if *rs_encoding == encoding_rs::UTF_16LE => {
+                OutputTarget::Utf16Le
+            }
+            Encoding::Whatwg(rs_encoding, _) if *rs_encoding == encoding_rs::UTF_16BE => {
+                OutputTarget::Utf16Be
+            }
+            Encoding::Whatwg(rs_encoding, _) => OutputTarget::Encoder(rs_encoding.new_encoder()),
+            Encoding::Utf32Le(_) => OutputTarget::Utf32Le,
+            Encoding::Utf32Be(_) => OutputTarget::Utf32Be,
+            Encoding::Ebcdic(code_page, _) => {
+                OutputTarget::Ebcdic(code_page, Box::new(code_page.encoding_table()))
+            }
+        }
+    }
+
+    // Encodes as much of the input as possible. If a character can't be represented in the
+    // output encoding, encoding stops after it, and that character and the number of input bytes
+    // consumed (including it) are returned.
+    fn encode(&mut self, input: &str, output: &mut Vec<u8>, last: bool) -> Option<(char, usize)> {
+        match self {
+            OutputTarget::Utf16Le => {
+                for unit in input.encode_utf16() {
+                    output.extend_from_slice(&unit.to_le_bytes());
+                }
+                None
+            }
+            OutputTarget::Utf16Be => {
+                for unit in input.encode_utf16() {
+                    output.extend_from_slice(&unit.to_be_bytes());
+                }
+                None
+            }
+            OutputTarget::Utf32Le => {
+                for c in input.chars() {
+                    output.extend_from_slice(&(c as u32).to_le_bytes());
+                }
+                None
+            }
+            OutputTarget::Utf32Be => {
+                for c in input.chars() {
+                    output.extend_from_slice(&(c as u32).to_be_bytes());
+                }
+                None
+            }
+            OutputTarget::Ebcdic(_, encoding_table) => {
+                for (char_start, c) in input.char_indices() {
+                    if c as u32 > 0xFF {
+                        return Some((c, char_start + c.len_utf8()));
+                    }
+                    output.push(encoding_table[c as usize]);
+                }
+                None
+            }
+            OutputTarget::Encoder(encoder) => {
+                let mut bytes_read = 0;
+                loop {
+                    let remaining = &input[bytes_read..];
+                    let needed = encoder
+                        .max_buffer_length_from_utf8_without_replacement(remaining.len())
+                        .unwrap_or(remaining.len() * 4 + 8);
+                    output.reserve(needed);
+                    let (result, read) = encoder
+                        .encode_from_utf8_to_vec_without_replacement(remaining, output, last);
+                    bytes_read += read;
+                    match result {
+                        encoding_rs::EncoderResult::InputEmpty => return None,
+                        encoding_rs::EncoderResult::Unmappable(c) => return Some((c, bytes_read)),
+                        encoding_rs::EncoderResult::OutputFull => {}
+                    }
+                }
+            }
+        }
+    }
+
+    fn encoding_name(&self) -> &'static str {
+        match self {
+            OutputTarget::Encoder(encoder) => encoder.encoding().name(),
+            OutputTarget::Utf16Le => "UTF-16LE",
+            OutputTarget::Utf16Be => "UTF-16BE",
+            OutputTarget::Utf32Le => "UTF-32LE",
+            OutputTarget::Utf32Be => "UTF-32BE",
+            OutputTarget::Ebcdic(code_page, _) => code_page.name(),
+        }
+    }
+}
+
+// Just enough of the xml grammar to know whether a character reference is allowed at the current
+// position in the output: they're recognized in character data, attribute values, and the entity
+// values and attribute defaults of the internal subset, but not in names, comments, processing
+// instructions, CDATA sections or the rest of the document type declaration.
+#[derive(Clone, Copy, PartialEq)]
+enum Markup {
+    Text,
+    // Just after '<'
+    TagOpen,
+    // Element name of a start or end tag
+    Name,
+    // Inside a tag, outside of attribute values
+    Tag,
+    AttrValue(char),
+    // Just after "<!"
+    DeclOpen,
+    Comment,
+    CData,
+    ProcessingInstruction,
+    // Inside the document type declaration, at the given depth of '[' nesting
+    Doctype(usize),
+    // A quoted literal in the document type declaration, and whether it's an EntityValue or
+    // AttValue, which take references, rather than a SystemLiteral or PubidLiteral
+    DoctypeLiteral(usize, char, bool),
+    DoctypeComment(usize),
+    DoctypeProcessingInstruction(usize),
+}
+
+struct MarkupContext {
+    state: Markup,
+    // Most recent chars seen in states that end with (or are decided by) a multi-char delimiter,
+    // or the markup declaration so far in the internal subset
+    recent: Vec<char>,
+}
+
+impl MarkupContext {
+    fn new() -> Self {
+        MarkupContext {
+            state: Markup::Text,
+            recent: Vec::with_capacity(8),
+        }
+    }
+
+    fn advance(&mut self, input: &str) {
+        for c in input.chars() {
+            self.advance_char(c);
+        }
+    }
+
+    fn advance_char(&mut self, c: char) {
+        let next = match self.state {
+            Markup::Text if c == '<' => Markup::TagOpen,
+            Markup::Text => Markup::Text,
+            Markup::TagOpen => match c {
+                '?' => Markup::ProcessingInstruction,
+                '!' => Markup::DeclOpen,
+                _ => Markup::Name,
+            },
+            Markup::Name | Markup::Tag if c == '>' => Markup::Text,
+            Markup::Name if c == '/' || c.is_whitespace() => Markup::Tag,
+            Markup::Name => Markup::Name,
+            Markup::Tag if c == '"' || c == '\'' => Markup::AttrValue(c),
+            Markup::Tag => Markup::Tag,
+            Markup::AttrValue(quote) if c == quote => Markup::Tag,
+            Markup::AttrValue(quote) => Markup::AttrValue(quote),
+            Markup::DeclOpen => {
+                self.recent.push(c);
+                let seen: String = self.recent.iter().collect();
+                if seen == "--" {
+                    Markup::Comment
+                } else if seen == "[CDATA[" {
+                    Markup::CData
+                } else if "--".starts_with(&seen) || "[CDATA[".starts_with(&seen) {
+                    Markup::DeclOpen
+                } else {
+                    Markup::Doctype(0)
+                }
+            }
+            Markup::Comment => self.until_delimiter(c, "-->", Markup::Text),
+            Markup::CData => self.until_delimiter(c, "]]>", Markup::Text),
+            Markup::ProcessingInstruction => self.until_delimiter(c, "?>", Markup::Text),
+            Markup::Doctype(depth) => self.advance_doctype(c, depth),
+            Markup::DoctypeLiteral(depth, quote, _) if c == quote => Markup::Doctype(depth),
+            Markup::DoctypeLiteral(..) => self.state,
+            Markup::DoctypeComment(depth) => self.until_delimiter(c, "-->", Markup::Doctype(depth)),
+            Markup::DoctypeProcessingInstruction(depth) => {
+                self.until_delimiter(c, "?>", Markup::Doctype(depth))
+            }
+        };
+        // The markup declaration so far has to outlast its literals
+        let keep_recent = matches!(
+            (self.state, next),
+            (_, Markup::DeclOpen)
+                | (Markup::Doctype(_), Markup::DoctypeLiteral(..))
+                | (Markup::DoctypeLiteral(..), Markup::Doctype(_))
+        );
+        if next != self.state && !keep_recent {
+            self.recent.clear();
+        }
+        self.state = next;
+    }
+
+    fn advance_doctype(&mut self, c: char, depth: usize) -> Markup {
+        match c {
+            '[' => return Markup::Doctype(depth + 1),
+            ']' => return Markup::Doctype(depth.saturating_sub(1)),
+            '>' if depth == 0 => return Markup::Text,
+            '"' | '\'' => return Markup::DoctypeLiteral(depth, c, self.literal_takes_refs(depth)),
+            '<' | '>' => self.recent.clear(),
+            _ => {}
+        }
+        // Only the internal subset has markup declarations to keep track of
+        if depth == 0 {
+            return Markup::Doctype(depth);
+        }
+        if c != '>' {
+            self.recent.push(c);
+        }
+        if self.recent == ['<', '!', '-', '-'] {
+            Markup::DoctypeComment(depth)
+        } else if self.recent == ['<', '?'] {
+            Markup::DoctypeProcessingInstruction(depth)
+        } else {
+            Markup::Doctype(depth)
+        }
+    }
+
+    // Whether a literal that starts here is an EntityValue or an AttValue (an attribute default),
+    // going by the markup declaration so far
+    fn literal_takes_refs(&self, depth: usize) -> bool {
+        if depth == 0 {
+            return false;
+        }
+        let declaration: String = self.recent.iter().collect();
+        let words: Vec<&str> = declaration.split_whitespace().collect();
+        match words.first() {
+            // The value follows the name straight away, where an external entity has SYSTEM
+            // or PUBLIC
+            Some(&"<!ENTITY") => words.len() == 2 || (words.len() == 3 && words[1] == "%"),
+            Some(&"<!ATTLIST") => true,
+            _ => false,
+        }
+    }
+
+    fn until_delimiter(&mut self, c: char, delimiter: &str, after: Markup) -> Markup {
+        self.recent.push(c);
+        if self.recent.len() > delimiter.len() {
+            self.recent.remove(0);
+        }
+        if self.recent.iter().cloned().eq(delimiter.chars()) {
+            after
+        } else {
+            self.state
+        }
+    }
+
+    fn allows_char_ref(&self) -> bool {
+        matches!(
+            self.state,
+            Markup::Text | Markup::AttrValue(_) | Markup::DoctypeLiteral(_, _, true)
+        )
+    }
+
+    fn describe(&self) -> &'static str {
+        match self.state {
+            Markup::Text | Markup::AttrValue(_) => "character data",
+            Markup::TagOpen | Markup::Name | Markup::Tag => "element and attribute names",
+            Markup::Comment | Markup::DoctypeComment(_) => "comments",
+            Markup::CData => "CDATA sections",
+            Markup::ProcessingInstruction | Markup::DoctypeProcessingInstruction(_) => {
+                "processing instructions"
+            }
+            Markup::DeclOpen | Markup::Doctype(_) | Markup::DoctypeLiteral(..) => {
+                "document type declarations"
+            }
+        }
+    }
+}
+
+// Tracks what has been written at the start of the document, so that we can refuse to produce
+// output that enc_detect::detect_encoding_with_suggestion would not be able to read back.
+#[derive(Default)]
+struct Prolog {
+    bom: bool,
+    xml_decl: bool,
+    // The first bytes of document content are held back until we know whether the content
+    // supplies its own xml declaration. None once that's been decided.
+    leading: Option<String>,
+    content_started: bool,
+}
+
+/// Accepts utf-8 and writes it to the wrapped writer in the requested output encoding.
+///
+/// Output is buffered; it is flushed when the buffer fills, on `flush()`, on `into_inner()`, and
+/// on drop. Dropping the writer ignores any errors, including input that ends partway through a
+/// utf-8 sequence, so call `into_inner()` to see them.
+pub struct EncodingWriter<W: Write> {
+    inner: Option<W>,
+    encoding: Encoding,
+    target: OutputTarget,
+    prolog: Prolog,
+    markup: MarkupContext,
+    // Trailing bytes of a utf-8 sequence that was split across calls to write()
+    pending: Vec<u8>,
+    output_buf: Vec<u8>,
+    capacity: usize,
+}
+
+impl<W: Write> EncodingWriter<W> {
+    /// Writes the byte order mark for the output encoding. Must precede all other output.
+    pub fn write_bom(&mut self) -> Result<()> {
+        if self.prolog.bom || self.prolog.xml_decl || self.prolog.content_started {
+            return Err(Error::MisplacedBom);
+        }
+        let bom = self
+            .encoding
+            .get_bom()
+            .ok_or_else(|| Error::NoBom(self.encoding.get_name()))?;
+        self.output_buf.extend_from_slice(bom);
+        self.prolog.bom = true;
+        Ok(())
+    }
+
+    /// Writes `<?xml version="1.0" encoding="..."?>` naming the output encoding. Must precede
+    /// all document content.
+    pub fn write_xml_decl(&mut self) -> Result<()> {
+        if self.prolog.xml_decl || self.prolog.content_started {
+            return Err(Error::MisplacedXmlDecl);
+        }
+        let xml_decl = format!(
+            "<?xml version=\"1.0\" encoding=\"{}\"?>",
+            self.encoding.get_name()
+        );
+        self.encode_content(&xml_decl)?;
+        self.prolog.xml_decl = true;
+        Ok(())
+    }
+
+    pub fn get_ref(&self) -> &W {
+        self.inner.as_ref().expect("writer used after into_inner")
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        self.inner.as_mut().expect("writer used after into_inner")
+    }
+
+    /// Finishes the output stream and returns the wrapped writer.
+    pub fn into_inner(mut self) -> Result<W> {
+        if !self.pending.is_empty() {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Input ended in the middle of a utf-8 sequence.",
+            )));
+        }
+        self.finish_leading()?;
+        self.target.encode("", &mut self.output_buf, true);
+        self.flush_buf()?;
+        Ok(self.inner.take().expect("writer used after into_inner"))
+    }
+
+    // Checks the start of the document against the prolog the writer has produced, then releases
+    // the held-back content to the encoder
+    fn finish_leading(&mut self) -> Result<()> {
+        let leading = match self.prolog.leading.take() {
+            Some(leading) => leading,
+            None => {
+                if self.prolog.content_started {
+                    return Ok(());
+                }
+                // Empty document - the prolog still has to be readable
+                self.prolog.content_started = true;
+                String::new()
+            }
+        };
+        let content_xml_decl = if starts_with_xml_decl(&leading) {
+            match leading.find("?>") {
+                Some(end) => Some(XmlDecl::parse(&leading[..end + 2])?),
+                None if leading.len() > 256 => {
+                    return Err(Error::MalformedXmlDecl(
+                        "no closing '?>' within 256 characters".to_string(),
+                    ))
+                }
+                None => {
+                    return Err(Error::MalformedXmlDecl(
+                        "document ends before the closing '?>'".to_string(),
+                    ))
+                }
+            }
+        } else {
+            None
+        };
+        self.check_prolog(content_xml_decl.as_ref())?;
+        self.encode_content(&leading)?;
+        if self.output_buf.len() >= self.capacity {
+            self.flush_buf()?;
+        }
+        Ok(())
+    }
+
+    // Mirrors the decisions made by detect_encoding_with_suggestion
+    fn check_prolog(&self, content_xml_decl: Option<&XmlDecl>) -> Result<()> {
+        if content_xml_decl.is_some() && self.prolog.xml_decl {
+            return Err(Error::MisplacedXmlDecl);
+        }
+
+        let declared_encoding = content_xml_decl.and_then(|xml_decl| xml_decl.encoding.as_ref());
+        if let Some(encoding_name) = declared_encoding {
+            // The reader decodes EBCDIC in whichever code page is declared, so only the output's
+            // own code page will do
+            let compatible = match &self.encoding {
+                Encoding::Ebcdic(code_page, _) => {
+                    ebcdic::for_label(encoding_name).map(|declared| declared.name())
+                        == Some(code_page.name())
+                }
+                _ => self.encoding.encoding_decl_is_compatible(encoding_name)?,
+            };
+            if !compatible {
+                return Err(Error::DeclOutputConflict {
+                    declared: encoding_name.clone(),
+                    output: self.encoding.get_name(),
+                });
+            }
+        }
+
+        let has_encoding_decl = self.prolog.xml_decl || declared_encoding.is_some();
+        let has_xml_decl = self.prolog.xml_decl || content_xml_decl.is_some();
+        let is_utf = self.encoding.get_bom().is_some();
+        if !is_utf && !has_encoding_decl {
+            return Err(Error::EncodingDeclRequired(self.encoding.get_name()));
+        }
+        if self.encoding.get_char_width() > 1 && !self.prolog.bom && !has_xml_decl {
+            return Err(Error::BomOrDeclRequired(self.encoding.get_name()));
+        }
+        Ok(())
+    }
+
+    fn flush_buf(&mut self) -> io::Result<()> {
+        if let Some(inner) = self.inner.as_mut() {
+            inner.write_all(&self.output_buf)?;
+        }
+        self.output_buf.clear();
+        Ok(())
+    }
+
+    fn encode_str(&mut self, input: &str) -> Result<()> {
+        if !self.prolog.content_started {
+            self.prolog.content_started = true;
+            self.prolog.leading = Some(String::new());
+        }
+        if let Some(mut leading) = self.prolog.leading.take() {
+            leading.push_str(input);
+            if !leading_is_decided(&leading) {
+                self.prolog.leading = Some(leading);
+                return Ok(());
+            }
+            self.prolog.leading = Some(leading);
+            return self.finish_leading();
+        }
+
+        self.encode_content(input)?;
+        if self.output_buf.len() >= self.capacity {
+            self.flush_buf()?;
+        }
+        Ok(())
+    }
+
+    // Encodes document content, replacing characters that the output encoding can't represent
+    // with character references where the xml grammar allows it
+    fn encode_content(&mut self, input: &str) -> Result<()> {
+        let mut remaining = input;
+        while let Some((c, bytes_read)) = self.target.encode(remaining, &mut self.output_buf, false)
+        {
+            let char_start = bytes_read - c.len_utf8();
+            self.markup.advance(&remaining[..char_start]);
+            if !self.markup.allows_char_ref() {
+                return Err(Error::UnencodableChar {
+                    c,
+                    encoding: self.target.encoding_name().to_string(),
+                    context: self.markup.describe(),
+                });
+            }
+            self.markup.advance_char(c);
+            let char_ref = format!("&#x{:X};", c as u32);
+            self.target.encode(&char_ref, &mut self.output_buf, false);
+            remaining = &remaining[bytes_read..];
+        }
+        self.markup.advance(remaining);
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for EncodingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut input = std::mem::take(&mut self.pending);
+        input.extend_from_slice(buf);
+
+        let valid_len = match std::str::from_utf8(&input) {
+            Ok(_) => input.len(),
+            // Incomplete sequence at the end of the buffer - hold onto it for the next write
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(e) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Input is not valid utf-8: {}", e),
+                ))
+            }
+        };
+        self.pending.extend_from_slice(&input[valid_len..]);
+        let valid = std::str::from_utf8(&input[..valid_len]).expect("validated above");
+        self.encode_str(valid)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_buf()?;
+        if let Some(inner) = self.inner.as_mut() {
+            inner.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Drop for EncodingWriter<W> {
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            // Like into_inner, minus the errors, so a stateful encoding still gets back to ASCII.
+            // An incomplete utf-8 sequence in pending is lost.
+            let _ = self.finish_leading();
+            self.target.encode("", &mut self.output_buf, true);
+            let _ = self.flush_buf();
+        }
+    }
+}
+
+const XML_DECL_PREFIX: &str = "<?xml";
+
+fn starts_with_xml_decl(content: &str) -> bool {
+    content.starts_with(XML_DECL_PREFIX)
+        && content[XML_DECL_PREFIX.len()..]
+            .chars()
+            .next()
+            .map_or(false, char::is_whitespace)
+}
+
+// Whether enough of the document has been seen to tell if (and where) it declares itself
+fn leading_is_decided(content: &str) -> bool {
+    if content.len() <= XML_DECL_PREFIX.len() {
+        return !XML_DECL_PREFIX.starts_with(content);
+    }
+    !starts_with_xml_decl(content) || content.contains("?>") || content.len() > 256
+}
+
+#[cfg(test)]
+mod writer_tests {
+    use super::*;
+
+    use reader;
+    use std::io::Read;
+
+    fn encode_doc(doc: &[u8], encoding_name: &str) -> Vec<u8> {
+        let mut encoded: Vec<u8> = Vec::new();
+        {
+            let mut encoding_writer = with_capacity_and_output_encoding(
+                &mut encoded,
+                DEFAULT_BUF_SIZE,
+                Some(encoding_name.to_string()),
+            )
+            .expect("Failed initializing write buffer");
+            encoding_writer
+                .write_all(doc)
+                .expect("Failed encoding output data");
+            encoding_writer
+                .into_inner()
+                .expect("Failed flushing output data");
+        }
+        encoded
+    }
+
+    // The error from the writer that a Write call failed with
+    fn write_error(result: io::Result<()>) -> Error {
+        let e = result.expect_err("Wrote output that can't be read back");
+        match e.into_inner().map(|e| e.downcast::<Error>()) {
+            Some(Ok(e)) => *e,
+            _ => panic!("Not an error from the writer"),
+        }
+    }
+
+    #[test]
+    fn test_utf8() {
+        let utf8_validation =
+            include_bytes!("../tests/validation/utf8_xmldecl_encodingdecl.xml").to_vec();
+        let utf8_bytes = include_bytes!("../tests/utf8/doc_xmldecl_encodingdecl.xml").to_vec();
+
+        assert_eq!(utf8_bytes, encode_doc(&utf8_validation, "utf-8"));
+    }
+
+    #[test]
+    fn test_utf16le() {
+        let utf16_validation =
+            include_bytes!("../tests/validation/utf16le_xmldecl_encodingdecl.xml").to_vec();
+        let utf16_bytes = include_bytes!("../tests/utf16le/doc_xmldecl_encodingdecl.xml").to_vec();
+
+        assert_eq!(utf16_bytes, encode_doc(&utf16_validation, "utf-16le"));
+    }
+
+    #[test]
+    fn test_utf16be() {
+        let utf16_validation =
+            include_bytes!("../tests/validation/utf16be_xmldecl_encodingdecl.xml").to_vec();
+        let utf16_bytes = include_bytes!("../tests/utf16be/doc_xmldecl_encodingdecl.xml").to_vec();
+
+        assert_eq!(utf16_bytes, encode_doc(&utf16_validation, "utf-16be"));
+    }
+
+    #[test]
+    fn test_utf32le() {
+        let utf32_validation =
+            include_bytes!("../tests/validation/utf32le_xmldecl_encodingdecl.xml").to_vec();
+        let utf32_bytes = include_bytes!("../tests/utf32le/doc_xmldecl_encodingdecl.xml").to_vec();
+
+        assert_eq!(utf32_bytes, encode_doc(&utf32_validation, "utf-32le"));
+    }
+
+    #[test]
+    fn test_utf32be() {
+        let utf32_validation =
+            include_bytes!("../tests/validation/utf32be_xmldecl_encodingdecl.xml").to_vec();
+        let utf32_bytes = include_bytes!("../tests/utf32be/doc_xmldecl_encodingdecl.xml").to_vec();
+
+        assert_eq!(utf32_bytes, encode_doc(&utf32_validation, "utf-32be"));
+    }
+
+    #[test]
+    fn test_ibm1047() {
+        let ebcdic_validation =
+            include_bytes!("../tests/validation/ibm1047_xmldecl_encodingdecl.xml").to_vec();
+        let ebcdic_bytes = include_bytes!("../tests/ibm1047/doc_xmldecl_encodingdecl.xml").to_vec();
+
+        assert_eq!(ebcdic_bytes, encode_doc(&ebcdic_validation, "ibm1047"));
+    }
+
+    #[test]
+    fn test_split_utf8_sequence() {
+        // 'Ë' is two bytes in utf-8; write them in separate calls
+        let mut encoded: Vec<u8> = Vec::new();
+        {
+            let mut encoding_writer =
+                with_capacity_and_output_encoding(&mut encoded, 1, Some("utf-16le".to_string()))
+                    .expect("Failed initializing write buffer");
+            encoding_writer.write_bom().unwrap();
+            encoding_writer.write_all(&[0xC3]).unwrap();
+            encoding_writer.write_all(&[0x8B]).unwrap();
+            encoding_writer.into_inner().unwrap();
+        }
+        assert_eq!(vec![0xFF, 0xFE, 0xCB, 0x00], encoded);
+    }
+
+    #[test]
+    fn test_unsupported_encoding() {
+        let mut encoded: Vec<u8> = Vec::new();
+        assert!(
+            with_capacity_and_output_encoding(&mut encoded, 8, Some("bogus".to_string())).is_err()
+        );
+    }
+
+    #[test]
+    fn test_utf16le_bom_xmldecl() {
+        let mut encoded: Vec<u8> = Vec::new();
+        {
+            let mut encoding_writer = with_capacity_and_output_encoding(
+                &mut encoded,
+                DEFAULT_BUF_SIZE,
+                Some("utf-16le".to_string()),
+            )
+            .expect("Failed initializing write buffer");
+            encoding_writer.write_bom().unwrap();
+            encoding_writer.write_xml_decl().unwrap();
+            encoding_writer
+                .write_all("<doc>Ë</doc>\n".as_bytes())
+                .unwrap();
+            encoding_writer.into_inner().unwrap();
+        }
+        assert_eq!(&[0xFF, 0xFE, b'<', 0x00, b'?', 0x00], &encoded[..6]);
+
+        let mut decoding_reader = reader::new(&encoded as &[u8]).unwrap();
+        let mut utf8_encoded_doc: String = String::new();
+        decoding_reader
+            .read_to_string(&mut utf8_encoded_doc)
+            .expect("Failed decoding input data");
+        assert_eq!(
+            "<?xml version=\"1.0\" encoding=\"utf-16le\"?><doc>Ë</doc>\n",
+            utf8_encoded_doc
+        );
+    }
+
+    #[test]
+    fn test_utf16be_without_bom_or_xmldecl() {
+        // Nothing in the output would tell the reader what the encoding is
+        let mut encoded: Vec<u8> = Vec::new();
+        let mut encoding_writer =
+            with_capacity_and_output_encoding(&mut encoded, 8, Some("utf-16be".to_string()))
+                .unwrap();
+        match write_error(encoding_writer.write_all(b"<doc/>")) {
+            Error::BomOrDeclRequired(name) => assert_eq!(name, "utf-16be"),
+            e => panic!("Unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_bom_incompatible_with_content_xmldecl() {
+        let mut encoded: Vec<u8> = Vec::new();
+        let mut encoding_writer =
+            with_capacity_and_output_encoding(&mut encoded, 8, Some("utf-16le".to_string()))
+                .unwrap();
+        encoding_writer.write_bom().unwrap();
+        match write_error(
+            encoding_writer.write_all(b"<?xml version='1.0' encoding='utf-8'?><doc/>"),
+        ) {
+            Error::DeclOutputConflict { declared, output } => {
+                assert_eq!(declared, "utf-8");
+                assert_eq!(output, "utf-16le");
+            }
+            e => panic!("Unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_utf_family_names_in_content_xmldecl() {
+        for &(output, declared) in &[
+            ("utf-16le", "UTF-16"),
+            ("utf-16be", "UTF-16"),
+            ("utf-32le", "UTF-32"),
+            ("utf-32be", "UTF-32"),
+        ] {
+            let doc = format!("<?xml version='1.0' encoding='{}'?><doc>Ë</doc>", declared);
+            let encoded = encode_doc(doc.as_bytes(), output);
+            let mut decoding_reader = reader::new(&encoded as &[u8]).unwrap();
+            assert_eq!(decoding_reader.detection_report().encoding, output);
+            let mut utf8_encoded_doc: String = String::new();
+            decoding_reader
+                .read_to_string(&mut utf8_encoded_doc)
+                .expect("Failed decoding input data");
+            assert_eq!(utf8_encoded_doc, doc);
+        }
+    }
+
+    #[test]
+    fn test_content_xmldecl_split_across_writes() {
+        let mut encoded: Vec<u8> = Vec::new();
+        {
+            let mut encoding_writer =
+                with_capacity_and_output_encoding(&mut encoded, 8, Some("utf-16be".to_string()))
+                    .unwrap();
+            encoding_writer.write_all(b"<?xm").unwrap();
+            encoding_writer
+                .write_all(b"l version='1.0' encoding='utf-16be'?")
+                .unwrap();
+            encoding_writer.write_all(b"><doc/>").unwrap();
+            encoding_writer.into_inner().unwrap();
+        }
+        let utf16_bytes = include_bytes!("../tests/utf16be/doc_xmldecl_encodingdecl.xml");
+        assert_eq!(
+            &utf16_bytes[..encoded.len() - 12],
+            &encoded[..encoded.len() - 12]
+        );
+    }
+
+    #[test]
+    fn test_malformed_content_xmldecl() {
+        // The reader wouldn't take "myencoding" for an encoding declaration either
+        let mut encoded: Vec<u8> = Vec::new();
+        let mut encoding_writer =
+            with_capacity_and_output_encoding(&mut encoded, 8, Some("koi8-r".to_string())).unwrap();
+        match write_error(encoding_writer.write_all(b"<?xml version='1.0' myencoding='koi8-r'?>")) {
+            Error::MalformedXmlDecl(_) => {}
+            e => panic!("Unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_duplicate_xmldecl() {
+        let mut encoded: Vec<u8> = Vec::new();
+        let mut encoding_writer = new(&mut encoded).unwrap();
+        encoding_writer.write_xml_decl().unwrap();
+        match write_error(encoding_writer.write_all(b"<?xml version='1.0'?><doc/>")) {
+            Error::MisplacedXmlDecl => {}
+            e => panic!("Unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_prolog_after_content() {
+        let mut encoded: Vec<u8> = Vec::new();
+        let mut encoding_writer = new(&mut encoded).unwrap();
+        encoding_writer.write_all(b"<doc/>").unwrap();
+        match encoding_writer.write_bom() {
+            Err(Error::MisplacedBom) => {}
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("Wrote a byte order mark after content"),
+        }
+        match encoding_writer.write_xml_decl() {
+            Err(Error::MisplacedXmlDecl) => {}
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("Wrote an xml declaration after content"),
+        }
+    }
+
+    #[test]
+    fn test_char_refs() {
+        let mut encoded: Vec<u8> = Vec::new();
+        {
+            let mut encoding_writer =
+                with_capacity_and_output_encoding(&mut encoded, 8, Some("iso-8859-1".to_string()))
+                    .unwrap();
+            encoding_writer.write_xml_decl().unwrap();
+            encoding_writer
+                .write_all("<doc a='Ω\"' b=\"'Ω\">ËΩ<!-- Ë --><![CDATA[Ë]]></doc>".as_bytes())
+                .unwrap();
+            encoding_writer.into_inner().unwrap();
+        }
+        let mut expected: Vec<u8> = b"<?xml version=\"1.0\" encoding=\"windows-1252\"?>".to_vec();
+        expected.extend_from_slice(b"<doc a='&#x3A9;\"' b=\"'&#x3A9;\">\xCB&#x3A9;");
+        expected.extend_from_slice(b"<!-- \xCB --><![CDATA[\xCB]]></doc>");
+        assert_eq!(expected, encoded);
+    }
+
+    #[test]
+    fn test_char_ref_in_name() {
+        let mut encoded: Vec<u8> = Vec::new();
+        let mut encoding_writer =
+            with_capacity_and_output_encoding(&mut encoded, 8, Some("windows-1252".to_string()))
+                .unwrap();
+        encoding_writer.write_xml_decl().unwrap();
+        match write_error(encoding_writer.write_all("<docΩ/>".as_bytes())) {
+            Error::UnencodableChar { c, context, .. } => {
+                assert_eq!(c, 'Ω');
+                assert_eq!(context, "element and attribute names");
+            }
+            e => panic!("Unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_char_ref_in_comment() {
+        let mut encoded: Vec<u8> = Vec::new();
+        let mut encoding_writer =
+            with_capacity_and_output_encoding(&mut encoded, 8, Some("windows-1252".to_string()))
+                .unwrap();
+        encoding_writer.write_xml_decl().unwrap();
+        encoding_writer.write_all(b"<doc><!-").unwrap();
+        match write_error(encoding_writer.write_all("- Ω -->".as_bytes())) {
+            Error::UnencodableChar { c, context, .. } => {
+                assert_eq!(c, 'Ω');
+                assert_eq!(context, "comments");
+            }
+            e => panic!("Unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_char_ref_after_comment() {
+        let mut encoded: Vec<u8> = Vec::new();
+        {
+            let mut encoding_writer = with_capacity_and_output_encoding(
+                &mut encoded,
+                8,
+                Some("windows-1252".to_string()),
+            )
+            .unwrap();
+            encoding_writer.write_xml_decl().unwrap();
+            encoding_writer
+                .write_all("<doc><!-- - -> -->Ω</doc>".as_bytes())
+                .unwrap();
+            encoding_writer.into_inner().unwrap();
+        }
+        assert!(encoded.ends_with(b"<doc><!-- - -> -->&#x3A9;</doc>"));
+    }
+
+    #[test]
+    fn test_drop_finishes_encoding() {
+        let mut encoded: Vec<u8> = Vec::new();
+        {
+            let mut encoding_writer =
+                with_capacity_and_output_encoding(&mut encoded, 8, Some("iso-2022-jp".to_string()))
+                    .unwrap();
+            encoding_writer.write_xml_decl().unwrap();
+            encoding_writer
+                .write_all("<doc/>\u{65e5}".as_bytes())
+                .unwrap();
+        }
+        // Back to ASCII after the JIS X 0208 char
+        assert!(encoded.ends_with(b"<doc/>\x1B$BF|\x1B(B"));
+    }
+
+    #[test]
+    fn test_char_refs_in_internal_subset() {
+        let mut encoded: Vec<u8> = Vec::new();
+        {
+            let mut encoding_writer = with_capacity_and_output_encoding(
+                &mut encoded,
+                8,
+                Some("windows-1252".to_string()),
+            )
+            .unwrap();
+            encoding_writer.write_xml_decl().unwrap();
+            encoding_writer
+                .write_all(
+                    "<!DOCTYPE doc [<!ENTITY e \"Ω\"><!ENTITY % p 'Ω'><!-- ']' -->\
+                     <!ATTLIST doc a CDATA \"Ω\" b CDATA 'Ω'>]><doc>Ω</doc>"
+                        .as_bytes(),
+                )
+                .unwrap();
+            encoding_writer.into_inner().unwrap();
+        }
+        assert!(encoded.ends_with(
+            b"<!DOCTYPE doc [<!ENTITY e \"&#x3A9;\"><!ENTITY % p '&#x3A9;'><!-- ']' -->\
+              <!ATTLIST doc a CDATA \"&#x3A9;\" b CDATA '&#x3A9;'>]><doc>&#x3A9;</doc>"
+        ));
+    }
+
+    #[test]
+    fn test_char_ref_in_system_literal() {
+        let mut encoded: Vec<u8> = Vec::new();
+        let mut encoding_writer =
+            with_capacity_and_output_encoding(&mut encoded, 8, Some("windows-1252".to_string()))
+                .unwrap();
+        encoding_writer.write_xml_decl().unwrap();
+        match write_error(
+            encoding_writer.write_all("<!DOCTYPE doc [<!ENTITY e SYSTEM \"Ω.xml\">".as_bytes()),
+        ) {
+            Error::UnencodableChar { c, context, .. } => {
+                assert_eq!(c, 'Ω');
+                assert_eq!(context, "document type declarations");
+            }
+            e => panic!("Unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_legacy_encoding_requires_encodingdecl() {
+        let mut encoded: Vec<u8> = Vec::new();
+        let mut encoding_writer =
+            with_capacity_and_output_encoding(&mut encoded, 8, Some("koi8-r".to_string())).unwrap();
+        match encoding_writer.write_bom() {
+            Err(Error::NoBom(name)) => assert_eq!(name, "koi8-r"),
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("Wrote a byte order mark for an encoding that has none"),
+        }
+        match write_error(encoding_writer.write_all(b"<doc/>")) {
+            Error::EncodingDeclRequired(name) => assert_eq!(name, "koi8-r"),
+            e => panic!("Unexpected error: {}", e),
+        }
+    }
+}
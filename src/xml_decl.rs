@@ -0,0 +1,280 @@
+use std::fmt;
+
+use error::{Error, Result};
+
+/// The contents of an xml declaration, production \[23\] of the
+/// [xml 1.0 specification](https://www.w3.org/TR/xml/#NT-XMLDecl):
+///
+/// ```text
+/// XMLDecl ::= '<?xml' VersionInfo EncodingDecl? SDDecl? S? '?>'
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct XmlDecl {
+    /// The VersionNum, e.g. "1.0"
+    pub version: String,
+    /// The EncName, exactly as it was written
+    pub encoding: Option<String>,
+    /// Whether the SDDecl said "yes" or "no"
+    pub standalone: Option<bool>,
+}
+
+impl XmlDecl {
+    /// Parses a complete xml declaration, from the opening `<?xml` through the closing `?>`.
+    pub fn parse(decl: &str) -> Result<XmlDecl> {
+        let mut parser = Parser { decl, pos: 0 };
+        parser.expect("<?xml")?;
+
+        // VersionInfo ::= S 'version' Eq ("'" VersionNum "'" | '"' VersionNum '"')
+        parser.expect_whitespace()?;
+        parser.expect("version")?;
+        parser.expect_eq()?;
+        let version = parser.quoted("VersionNum", is_version_num)?;
+
+        // EncodingDecl ::= S 'encoding' Eq ('"' EncName '"' | "'" EncName "'" )
+        let mut encoding = None;
+        let mut had_whitespace = parser.skip_whitespace();
+        if had_whitespace && parser.peek("encoding") {
+            parser.expect("encoding")?;
+            parser.expect_eq()?;
+            encoding = Some(parser.quoted("EncName", is_enc_name)?);
+            had_whitespace = parser.skip_whitespace();
+        }
+
+        // SDDecl ::= S 'standalone' Eq (("'" ('yes' | 'no') "'") | ('"' ('yes' | 'no') '"'))
+        let mut standalone = None;
+        if had_whitespace && parser.peek("standalone") {
+            parser.expect("standalone")?;
+            parser.expect_eq()?;
+            let value = parser.quoted("'yes' or 'no'", |v| v == "yes" || v == "no")?;
+            standalone = Some(value == "yes");
+            parser.skip_whitespace();
+        }
+
+        parser.expect("?>")?;
+        if parser.pos < decl.len() {
+            return Err(parser.error("unexpected text after '?>'"));
+        }
+
+        Ok(XmlDecl {
+            version,
+            encoding,
+            standalone,
+        })
+    }
+}
+
+// Writes the declaration back out, always with double quotes and single spaces
+impl fmt::Display for XmlDecl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<?xml version=\"{}\"", self.version)?;
+        if let Some(encoding) = &self.encoding {
+            write!(f, " encoding=\"{}\"", encoding)?;
+        }
+        if let Some(standalone) = self.standalone {
+            write!(
+                f,
+                " standalone=\"{}\"",
+                if standalone { "yes" } else { "no" }
+            )?;
+        }
+        write!(f, "?>")
+    }
+}
+
+// VersionNum ::= '1.' [0-9]+
+fn is_version_num(value: &str) -> bool {
+    value.starts_with("1.") && value.len() > 2 && value[2..].bytes().all(|b| b.is_ascii_digit())
+}
+
+// EncName ::= [A-Za-z] ([A-Za-z0-9._] | '-')*
+fn is_enc_name(value: &str) -> bool {
+    let mut bytes = value.bytes();
+    match bytes.next() {
+        Some(first) if first.is_ascii_alphabetic() => {
+            bytes.all(|b| b.is_ascii_alphanumeric() || b == b'.' || b == b'_' || b == b'-')
+        }
+        _ => false,
+    }
+}
+
+// S ::= (#x20 | #x9 | #xD | #xA)+
+fn is_xml_whitespace(c: char) -> bool {
+    c == ' ' || c == '\t' || c == '\r' || c == '\n'
+}
+
+struct Parser<'a> {
+    decl: &'a str,
+    // Byte offset into decl
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.decl[self.pos..]
+    }
+
+    fn error(&self, reason: &str) -> Error {
+        // Errors are counted in chars so that they make sense whatever the input encoding was
+        let char_pos = self.decl[..self.pos].chars().count();
+        Error::MalformedXmlDecl(format!("{} at character {}", reason, char_pos))
+    }
+
+    fn peek(&self, literal: &str) -> bool {
+        self.rest().starts_with(literal)
+    }
+
+    fn expect(&mut self, literal: &str) -> Result<()> {
+        if !self.peek(literal) {
+            return Err(self.error(&format!("expected '{}'", literal)));
+        }
+        self.pos += literal.len();
+        Ok(())
+    }
+
+    fn skip_whitespace(&mut self) -> bool {
+        let start = self.pos;
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start_matches(is_xml_whitespace).len();
+        self.pos > start
+    }
+
+    fn expect_whitespace(&mut self) -> Result<()> {
+        if !self.skip_whitespace() {
+            return Err(self.error("expected whitespace"));
+        }
+        Ok(())
+    }
+
+    // Eq ::= S? '=' S?
+    fn expect_eq(&mut self) -> Result<()> {
+        self.skip_whitespace();
+        self.expect("=")?;
+        self.skip_whitespace();
+        Ok(())
+    }
+
+    fn quoted<F: Fn(&str) -> bool>(&mut self, what: &str, is_valid: F) -> Result<String> {
+        let quote = match self.rest().chars().next() {
+            Some(c) if c == '"' || c == '\'' => c,
+            _ => return Err(self.error(&format!("expected quoted {}", what))),
+        };
+        self.pos += 1;
+        let len = self
+            .rest()
+            .find(quote)
+            .ok_or_else(|| self.error(&format!("unterminated {}", what)))?;
+        let value = &self.rest()[..len];
+        if !is_valid(value) {
+            return Err(self.error(&format!("invalid {} {:?}", what, value)));
+        }
+        self.pos += len + 1;
+        Ok(value.to_string())
+    }
+}
+
+#[cfg(test)]
+mod xml_decl_tests {
+    use super::*;
+
+    #[test]
+    fn test_version_only() {
+        let decl = XmlDecl::parse("<?xml version='1.0'?>").expect("Failed parsing xmldecl");
+        assert_eq!(decl.version, "1.0");
+        assert_eq!(decl.encoding, None);
+        assert_eq!(decl.standalone, None);
+    }
+
+    #[test]
+    fn test_all_fields() {
+        let decl =
+            XmlDecl::parse("<?xml version=\"1.1\" encoding='Shift_JIS' standalone=\"yes\"?>")
+                .expect("Failed parsing xmldecl");
+        assert_eq!(decl.version, "1.1");
+        assert_eq!(decl.encoding, Some("Shift_JIS".to_string()));
+        assert_eq!(decl.standalone, Some(true));
+    }
+
+    #[test]
+    fn test_whitespace() {
+        let decl =
+            XmlDecl::parse("<?xml\tversion = '1.0'\r\n  encoding =\"utf-8\"\nstandalone= 'no'  ?>")
+                .expect("Failed parsing xmldecl");
+        assert_eq!(decl.version, "1.0");
+        assert_eq!(decl.encoding, Some("utf-8".to_string()));
+        assert_eq!(decl.standalone, Some(false));
+
+        let decl =
+            XmlDecl::parse("<?xml version='1.0' encoding=\"x\"?>").expect("Failed parsing xmldecl");
+        assert_eq!(decl.encoding, Some("x".to_string()));
+    }
+
+    #[test]
+    fn test_standalone_without_encoding() {
+        let decl = XmlDecl::parse("<?xml version='1.0' standalone='yes'?>")
+            .expect("Failed parsing xmldecl");
+        assert_eq!(decl.encoding, None);
+        assert_eq!(decl.standalone, Some(true));
+    }
+
+    #[test]
+    fn test_display() {
+        let decl = "<?xml version='1.0' standalone='no'?>";
+        assert_eq!(
+            XmlDecl::parse(decl).unwrap().to_string(),
+            "<?xml version=\"1.0\" standalone=\"no\"?>"
+        );
+        let decl = "<?xml  version=\"1.1\"\tencoding='utf-16' ?>";
+        assert_eq!(
+            XmlDecl::parse(decl).unwrap().to_string(),
+            "<?xml version=\"1.1\" encoding=\"utf-16\"?>"
+        );
+    }
+
+    fn parse_error(decl: &str) -> String {
+        match XmlDecl::parse(decl) {
+            Err(Error::MalformedXmlDecl(reason)) => reason,
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("Accepted malformed xmldecl {:?}", decl),
+        }
+    }
+
+    #[test]
+    fn test_syntax_errors() {
+        assert_eq!(
+            parse_error("<?xml encoding='utf-8'?>"),
+            "expected 'version' at character 6"
+        );
+        assert_eq!(
+            parse_error("<?xml version='2.0'?>"),
+            "invalid VersionNum \"2.0\" at character 15"
+        );
+        assert_eq!(
+            parse_error("<?xml version=1.0?>"),
+            "expected quoted VersionNum at character 14"
+        );
+        assert_eq!(
+            parse_error("<?xml version='1.0' encoding='utf-8?>"),
+            "unterminated EncName at character 30"
+        );
+        assert_eq!(
+            parse_error("<?xml version='1.0' encoding='8bit'?>"),
+            "invalid EncName \"8bit\" at character 30"
+        );
+        assert_eq!(
+            parse_error("<?xml version='1.0'encoding='utf-8'?>"),
+            "expected '?>' at character 19"
+        );
+        assert_eq!(
+            parse_error("<?xml version='1.0' standalone='true'?>"),
+            "invalid 'yes' or 'no' \"true\" at character 32"
+        );
+        assert_eq!(
+            parse_error("<?xml version='1.0' standalone='no' encoding='utf-8'?>"),
+            "expected '?>' at character 36"
+        );
+        assert_eq!(
+            parse_error("<?xml version='1.0'?>x"),
+            "unexpected text after '?>' at character 21"
+        );
+    }
+}
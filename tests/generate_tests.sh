@@ -1,10 +1,12 @@
 #!/bin/bash
 
-encodings="utf8 utf16le utf16be"
+encodings="utf8 utf16le utf16be utf32le utf32be"
 
 utf8_bom='\xEF\xBB\xBF'
 utf16le_bom='\xFF\xFE'
 utf16be_bom='\xFE\xFF'
+utf32le_bom='\xFF\xFE\x00\x00'
+utf32be_bom='\x00\x00\xFE\xFF'
 
 for encoding in ${encodings}; do
 	bomvar="${encoding}_bom"
@@ -27,3 +29,14 @@ for encoding in ${encodings}; do
 	echo -ne "${!bomvar}" > ${encoding}_bom/doc_xmldecl_encodingdecl.xml
 	cat ${encoding}/doc_xmldecl_encodingdecl.xml >> ${encoding}_bom/doc_xmldecl_encodingdecl.xml
 done
+
+# These have no BOM, and can only be detected from the xml declaration
+decl_only_encodings="iso-8859-1 koi8-r windows-1251 shift_jis euc-jp iso-2022-jp euc-kr gbk gb18030 big5 ibm037 ibm500 ibm1047"
+
+for encoding in ${decl_only_encodings}; do
+	for variant in xmldecl xmldecl_encodingdecl; do
+		[ -f validation/${encoding}_${variant}.xml ] || continue
+		echo "Converting validation/${encoding}_${variant}.xml into ${encoding}/doc_${variant}.xml"
+		iconv -f utf8 -t ${encoding} validation/${encoding}_${variant}.xml > ${encoding}/doc_${variant}.xml
+	done
+done